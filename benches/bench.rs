@@ -6,7 +6,7 @@ use node_workers::WorkerPool;
 fn bench_fast_binary(c: &mut Criterion) {
   let mut group = c.benchmark_group("fast node binary");
   group.sample_size(30);
-  group.throughput(Throughput::Elements(1 as u64));
+  group.throughput(Throughput::Elements(1_u64));
   group.sampling_mode(SamplingMode::Flat);
 
   group.bench_function("standard commands", |b| {
@@ -33,7 +33,7 @@ fn bench_fast_binary(c: &mut Criterion) {
 fn bench_slow_binary(c: &mut Criterion) {
   let mut group = c.benchmark_group("slow node binary");
   group.sample_size(10);
-  group.throughput(Throughput::Elements(1 as u64));
+  group.throughput(Throughput::Elements(1_u64));
   group.sampling_mode(SamplingMode::Flat);
 
   group.bench_function("standard commands", |b| {
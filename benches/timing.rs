@@ -5,7 +5,7 @@ use std::process::Command;
 fn main() {
   fn standard_command(worker_name: &str) {
     Command::new("node")
-      .arg(&format!("benches/workers/{}", worker_name))
+      .arg(format!("benches/workers/{}", worker_name))
       .arg("30")
       .spawn()
       .unwrap()
@@ -14,7 +14,7 @@ fn main() {
   }
 
   let bm = BenchMan::new("timing");
-  for worker_name in vec!["fast", "slow"] {
+  for worker_name in ["fast", "slow"] {
     let std_name = &format!("{}-inner", worker_name);
 
     {
@@ -0,0 +1,14 @@
+interface TaskContext {
+    /** Aborted when the task is cancelled from Rust */
+    signal: AbortSignal;
+}
+declare type Task = (payload: any, context: TaskContext) => unknown | Promise<unknown>;
+declare type Tasks = Record<string, Task>;
+interface Options {
+    debug?: boolean;
+}
+/** Version of the protocol spoken with the Rust crate, reported when the worker is ready */
+export declare const PROTOCOL_VERSION = 1;
+export declare function bridge(tasks: Tasks, opts?: Options): void;
+export {};
+//# sourceMappingURL=bridge.d.ts.map
\ No newline at end of file
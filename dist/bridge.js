@@ -3,8 +3,12 @@ var __importDefault = (this && this.__importDefault) || function (mod) {
     return (mod && mod.__esModule) ? mod : { "default": mod };
 };
 Object.defineProperty(exports, "__esModule", { value: true });
-exports.bridge = void 0;
-const readline_1 = __importDefault(require("readline"));
+exports.bridge = exports.PROTOCOL_VERSION = void 0;
+const readline_1 = __importDefault(require("node:readline"));
+/** Version of the protocol spoken with the Rust crate, reported when the worker is ready */
+exports.PROTOCOL_VERSION = 1;
+/** Optional capabilities of this bridge */
+const FEATURES = ["async", "cancel", "heartbeat"];
 function bridge(tasks, opts = {}) {
     const rl = readline_1.default.createInterface({
         input: process.stdin,
@@ -19,6 +23,35 @@ function bridge(tasks, opts = {}) {
     let payloadStr = "";
     let payload = null;
     let payloadStart = null;
+    let controller = null;
+    async function runTask(task) {
+        const current = new AbortController();
+        controller = current;
+        let res;
+        try {
+            res = await task(payload, { signal: current.signal });
+        }
+        catch (err) {
+            if (!current.signal.aborted)
+                throw err;
+        }
+        finally {
+            controller = null;
+        }
+        if (current.signal.aborted) {
+            debug("task cancelled");
+            console.log("CANCELLED");
+            return;
+        }
+        if (res) {
+            const str = JSON.stringify(res);
+            const chunks = str.match(/.{1,1000}/g) || [];
+            for (const chunk of chunks) {
+                console.log(`RESULT_CHUNK: ${chunk}`);
+            }
+        }
+        console.log("OK");
+    }
     rl.on("line", function (line) {
         switch (line) {
             case "PAYLOAD_END":
@@ -32,6 +65,14 @@ function bridge(tasks, opts = {}) {
                 debug("payload :>> ", payload);
                 console.log("PAYLOAD_OK");
                 break;
+            case "CANCEL":
+                debug("cancelling task");
+                controller === null || controller === void 0 ? void 0 : controller.abort();
+                break;
+            case "PING":
+                // answered from the event loop, so a worker stuck in a synchronous loop doesn't reply
+                console.log("PONG");
+                break;
             default: {
                 if (line.startsWith("PAYLOAD_CHUNK:")) {
                     if (!payloadStart) {
@@ -43,24 +84,33 @@ function bridge(tasks, opts = {}) {
                     const cmd = line.replace("CMD:", "").trim();
                     const task = tasks[cmd];
                     if (!task) {
-                        throw new Error(`Task "${cmd}" not found for this worker`);
+                        debug(`task "${cmd}" not found`);
+                        console.log("TASK_NOT_FOUND");
+                        break;
                     }
                     debug("executing command: ", cmd);
-                    const res = task === null || task === void 0 ? void 0 : task(payload);
-                    if (res) {
-                        const str = JSON.stringify(res);
-                        const chunks = str.match(/.{1,1000}/g) || [];
-                        for (const chunk of chunks) {
-                            console.log(`RESULT_CHUNK: ${chunk}`);
-                        }
-                    }
-                    console.log("OK");
+                    runTask(task).catch((err) => {
+                        // a failing task crashes the worker, as if it threw synchronously
+                        process.nextTick(() => {
+                            throw err;
+                        });
+                    });
                 }
                 break;
             }
         }
     });
-    console.log("READY");
+    // stdin is closed when the Rust program exits, even if it was killed
+    rl.on("close", () => {
+        debug("stdin closed, exiting");
+        process.exit(0);
+    });
+    const handshake = {
+        version: exports.PROTOCOL_VERSION,
+        features: FEATURES,
+        tasks: Object.keys(tasks),
+    };
+    console.log(`READY ${JSON.stringify(handshake)}`);
 }
 exports.bridge = bridge;
 //# sourceMappingURL=bridge.js.map
\ No newline at end of file
@@ -0,0 +1,105 @@
+// src/js/bridge.ts
+import readline from "node:readline";
+var PROTOCOL_VERSION = 1;
+var FEATURES = ["async", "cancel", "heartbeat"];
+function bridge(tasks, opts = {}) {
+  const rl = readline.createInterface({
+    input: process.stdin,
+    output: process.stdout,
+    terminal: false
+  });
+  let debugOn = opts == null ? void 0 : opts.debug;
+  function debug(...args) {
+    if (debugOn)
+      console.log.call(console, ...args);
+  }
+  let payloadStr = "";
+  let payload = null;
+  let payloadStart = null;
+  let controller = null;
+  async function runTask(task) {
+    const current = new AbortController();
+    controller = current;
+    let res;
+    try {
+      res = await task(payload, { signal: current.signal });
+    } catch (err) {
+      if (!current.signal.aborted)
+        throw err;
+    } finally {
+      controller = null;
+    }
+    if (current.signal.aborted) {
+      debug("task cancelled");
+      console.log("CANCELLED");
+      return;
+    }
+    if (res) {
+      const str = JSON.stringify(res);
+      const chunks = str.match(/.{1,1000}/g) || [];
+      for (const chunk of chunks) {
+        console.log(`RESULT_CHUNK: ${chunk}`);
+      }
+    }
+    console.log("OK");
+  }
+  rl.on("line", function(line) {
+    switch (line) {
+      case "PAYLOAD_END":
+        payload = JSON.parse(payloadStr);
+        if (payload == null ? void 0 : payload._inner_payload) {
+          payload = payload == null ? void 0 : payload._inner_payload;
+        }
+        payloadStr = "";
+        debug("payload received in", Date.now() - payloadStart, "ms");
+        payloadStart = null;
+        debug("payload :>> ", payload);
+        console.log("PAYLOAD_OK");
+        break;
+      case "CANCEL":
+        debug("cancelling task");
+        controller == null ? void 0 : controller.abort();
+        break;
+      case "PING":
+        console.log("PONG");
+        break;
+      default: {
+        if (line.startsWith("PAYLOAD_CHUNK:")) {
+          if (!payloadStart) {
+            payloadStart = Date.now();
+          }
+          payloadStr += line.replace("PAYLOAD_CHUNK:", "").trim();
+        } else if (line.startsWith("CMD:")) {
+          const cmd = line.replace("CMD:", "").trim();
+          const task = tasks[cmd];
+          if (!task) {
+            debug(`task "${cmd}" not found`);
+            console.log("TASK_NOT_FOUND");
+            break;
+          }
+          debug("executing command: ", cmd);
+          runTask(task).catch((err) => {
+            process.nextTick(() => {
+              throw err;
+            });
+          });
+        }
+        break;
+      }
+    }
+  });
+  rl.on("close", () => {
+    debug("stdin closed, exiting");
+    process.exit(0);
+  });
+  const handshake = {
+    version: PROTOCOL_VERSION,
+    features: FEATURES,
+    tasks: Object.keys(tasks)
+  };
+  console.log(`READY ${JSON.stringify(handshake)}`);
+}
+export {
+  PROTOCOL_VERSION,
+  bridge
+};
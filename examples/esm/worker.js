@@ -0,0 +1,12 @@
+// an ES module, as the nearest package.json has "type": "module"
+import { setTimeout } from 'node:timers/promises';
+
+const startedAt = Date.now();
+await setTimeout(100);
+const bootTime = Date.now() - startedAt;
+
+// the bridge is preloaded as a global
+bridge({
+  bootTime: () => bootTime,
+  double: (n) => n * 2,
+});
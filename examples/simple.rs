@@ -3,12 +3,14 @@ use serde::Deserialize;
 use std::path::Path;
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 struct Property {
   pub key: String,
-  #[serde(alias = "type")]
-  pub propType: String,
+  #[serde(rename = "propType", alias = "type")]
+  pub prop_type: String,
 }
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 struct Interface {
   pub name: String,
   pub props: Vec<Property>,
@@ -34,10 +36,6 @@ fn main() {
   let interfaces = pool
     .perform::<Vec<Interface>, _>("getInterfaces", files)
     .unwrap();
-  let interfaces: Vec<Interface> = interfaces
-    .into_iter()
-    .map(|x| x.unwrap())
-    .flatten()
-    .collect();
+  let interfaces: Vec<Interface> = interfaces.into_iter().flat_map(|x| x.unwrap()).collect();
   println!("interfaces: {:#?}", interfaces);
 }
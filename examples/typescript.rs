@@ -2,8 +2,8 @@ use node_workers::{EmptyPayload, WorkerPool};
 
 fn main() {
   // Create a pool of 4 node workers
+  // TypeScript is loaded by node itself if it supports it, or with tsx or esbuild-register
   let mut pool = WorkerPool::setup("examples/worker.ts", 4);
-  pool.set_binary("node -r esbuild-register");
   pool.with_debug(true);
 
   pool
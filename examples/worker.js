@@ -51,6 +51,18 @@ bridge({
   error: () => {
     throw new Error('task failed');
   },
+  sleep: (ms, { signal }) => {
+    return new Promise((resolve, reject) => {
+      if (ms < 0) {
+        return reject(new Error('invalid duration'));
+      }
+      const timer = setTimeout(() => resolve(ms), ms);
+      signal.addEventListener('abort', () => {
+        clearTimeout(timer);
+        reject(new Error('task aborted'));
+      });
+    });
+  },
   getInterfaces
 });
 
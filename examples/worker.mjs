@@ -0,0 +1,13 @@
+import { bridge } from '../dist/bridge.mjs';
+
+// top-level await runs before the worker reports that it's ready
+const config = await new Promise((resolve) => {
+  setTimeout(() => resolve({ greeting: 'hello' }), 100);
+});
+
+bridge({
+  greet: (name) => `${config.greeting} ${name}`,
+  ping: () => {
+    console.log(`pong at ${new Date()}`);
+  },
+});
@@ -32,7 +32,7 @@ impl EmptyPayload {
   /// # }
   /// ```
   pub fn bulk(n: u32) -> Vec<EmptyPayload> {
-    (0..n).into_iter().map(|_| EmptyPayload::new()).collect()
+    (0..n).map(|_| EmptyPayload::new()).collect()
   }
 }
 impl Default for EmptyPayload {
@@ -0,0 +1,59 @@
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{error::WorkerError, managed_file::ManagedFile};
+
+/// Version of the protocol spoken with workers. Must match the version reported by the bridge.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// What a worker reports about its bridge when it's ready
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct BridgeInfo {
+  /// Protocol version of the bridge
+  pub version: u32,
+  /// Optional capabilities of the bridge, such as `cancel`
+  pub features: Vec<String>,
+  /// Names of the tasks of the worker script
+  pub tasks: Vec<String>,
+}
+
+/// Bridge info reported by the latest worker of a pool that became ready
+pub(crate) type SharedBridgeInfo = Arc<Mutex<Option<BridgeInfo>>>;
+
+/// Parse the data following `READY` in the handshake of a worker, and check that the bridge is compatible
+pub(crate) fn parse_handshake(handshake: Option<&str>) -> Result<BridgeInfo> {
+  let handshake = handshake.ok_or(WorkerError::IncompatibleBridge {
+    expected: PROTOCOL_VERSION,
+    found: None,
+  })?;
+  let info = serde_json::from_str::<BridgeInfo>(handshake).context("parsing worker handshake")?;
+  if info.version != PROTOCOL_VERSION {
+    bail!(WorkerError::IncompatibleBridge {
+      expected: PROTOCOL_VERSION,
+      found: Some(info.version),
+    });
+  }
+  Ok(info)
+}
+
+/// Compiled bridge, built from `src/js/bridge.ts`
+const BRIDGE_JS: &str = include_str!("../dist/bridge.js");
+
+/// Preload file shared by every pool, removed once the last one is dropped
+static PRELOAD: Mutex<Weak<ManagedFile>> = Mutex::new(Weak::new());
+
+/// Get the file preloaded in workers, for instance with `node -r`, which defines the bridge as a global
+/// so that worker scripts don't need the npm package, and always use the version of the bridge matching the crate.
+pub(crate) fn preload_file() -> Result<Arc<ManagedFile>> {
+  let mut preload = PRELOAD.lock().unwrap();
+  if let Some(file) = preload.upgrade() {
+    return Ok(file);
+  }
+  let source = format!("{}\nglobalThis.bridge = exports.bridge;\n", BRIDGE_JS);
+  // the extension makes runtimes defaulting to ES modules, such as deno, load the bridge as CommonJS
+  let file = Arc::new(ManagedFile::new("bridge.cjs", &source)?);
+  *preload = Arc::downgrade(&file);
+  Ok(file)
+}
@@ -0,0 +1,147 @@
+use std::{
+  collections::VecDeque,
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+use crate::error::WorkerError;
+
+/// When a pool stops spawning workers whose script keeps failing to boot, see `WorkerPool::set_circuit_breaker`
+/// ```
+/// use node_workers::CircuitBreakerPolicy;
+/// use std::time::Duration;
+///
+/// let policy = CircuitBreakerPolicy {
+///   failure_threshold: 3,
+///   cooldown: Duration::from_secs(30),
+///   ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CircuitBreakerPolicy {
+  /// Number of consecutive boot failures that open the circuit. Defaults to 5.
+  pub failure_threshold: u32,
+  /// Period the failures must happen within. Defaults to 30 seconds.
+  pub window: Duration,
+  /// How long the pool stays unavailable before a single worker is spawned to probe the script. Defaults to 5 seconds.
+  pub cooldown: Duration,
+}
+
+impl Default for CircuitBreakerPolicy {
+  fn default() -> Self {
+    CircuitBreakerPolicy {
+      failure_threshold: 5,
+      window: Duration::from_secs(30),
+      cooldown: Duration::from_secs(5),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+  policy: Option<CircuitBreakerPolicy>,
+  /// Time of the consecutive boot failures within the window
+  failures: VecDeque<Instant>,
+  /// Set while the circuit is open, until the cooldown is over
+  open_until: Option<Instant>,
+  /// Set while a worker is spawned to check whether the script was fixed
+  probe_started: Option<Instant>,
+}
+
+/// Circuit breaker shared by the workers of a pool.
+/// Once open, spawning a worker fails with `WorkerError::PoolUnavailable` until the cooldown is over.
+/// The next spawn is then a probe: the circuit closes if it boots, and opens again otherwise.
+#[derive(Debug)]
+pub(crate) struct CircuitBreaker {
+  state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+  pub fn new(policy: Option<CircuitBreakerPolicy>) -> Self {
+    CircuitBreaker {
+      state: Mutex::new(BreakerState {
+        policy,
+        ..Default::default()
+      }),
+    }
+  }
+
+  pub fn set_policy(&self, policy: Option<CircuitBreakerPolicy>) {
+    let mut state = self.state.lock().unwrap();
+    *state = BreakerState {
+      policy,
+      ..Default::default()
+    };
+  }
+
+  fn unavailable(state: &BreakerState, retry_at: Instant) -> WorkerError {
+    WorkerError::PoolUnavailable {
+      failures: state.failures.len() as u32,
+      retry_in: retry_at.saturating_duration_since(Instant::now()),
+    }
+  }
+
+  /// Fail if the circuit is open, without starting a probe
+  pub fn check(&self) -> Result<(), WorkerError> {
+    let state = self.state.lock().unwrap();
+    match state.open_until {
+      Some(until) if Instant::now() < until => Err(Self::unavailable(&state, until)),
+      _ => Ok(()),
+    }
+  }
+
+  /// Ask for the permission to spawn a process. Once the cooldown is over, only one process is allowed at a time
+  /// until one of them boots. A probe that never reports back is replaced after another cooldown.
+  pub fn acquire(&self) -> Result<(), WorkerError> {
+    let mut state = self.state.lock().unwrap();
+    let (until, cooldown) = match (state.open_until, &state.policy) {
+      (Some(until), Some(policy)) => (until, policy.cooldown),
+      _ => return Ok(()),
+    };
+    let now = Instant::now();
+    if now < until {
+      return Err(Self::unavailable(&state, until));
+    }
+    match state.probe_started {
+      Some(started) if now < started + cooldown => {
+        Err(Self::unavailable(&state, started + cooldown))
+      }
+      _ => {
+        state.probe_started = Some(now);
+        Ok(())
+      }
+    }
+  }
+
+  /// A process booted successfully, close the circuit
+  pub fn record_success(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.failures.clear();
+    state.open_until = None;
+    state.probe_started = None;
+  }
+
+  /// A process failed to boot. Returns true if this opened the circuit.
+  pub fn record_failure(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    let policy = match &state.policy {
+      Some(policy) => policy.clone(),
+      None => return false,
+    };
+    let now = Instant::now();
+    while state
+      .failures
+      .front()
+      .is_some_and(|at| now.duration_since(*at) > policy.window)
+    {
+      state.failures.pop_front();
+    }
+    state.failures.push_back(now);
+    let probing = state.probe_started.take().is_some();
+    if probing || state.failures.len() as u32 >= policy.failure_threshold {
+      state.open_until = Some(now + policy.cooldown);
+      return true;
+    }
+    false
+  }
+}
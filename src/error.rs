@@ -0,0 +1,163 @@
+use std::{fmt, process::ExitStatus, time::Duration};
+
+use crate::resource_limits::Resource;
+
+/// Errors specific to the pool. They are returned wrapped in an `anyhow::Error`
+/// and can be retrieved using `downcast_ref`.
+/// ```
+/// use node_workers::{QueuePolicy, WorkerError, WorkerPool};
+///
+/// let mut pool = WorkerPool::setup("examples/worker", 1);
+/// pool.set_queue_capacity(0);
+/// pool.set_queue_policy(QueuePolicy::FailFast);
+/// let err = pool.run_worker("fib2", 40).try_join().unwrap_err();
+/// assert!(matches!(
+///   err.downcast_ref::<WorkerError>(),
+///   Some(WorkerError::QueueFull { .. })
+/// ));
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum WorkerError {
+  /// The task queue of the pool reached its capacity
+  QueueFull { capacity: usize },
+  /// The task was removed from the queue to make room for a newer task (see `QueuePolicy::DropOldest`)
+  Dropped,
+  /// The task was cancelled before it completed
+  Cancelled,
+  /// The task was submitted after the pool was dropped, for instance through a `PerformIter` that outlived it
+  PoolClosed,
+  /// The bridge of the worker doesn't speak the protocol version of the crate.
+  /// `found` is `None` for bridges older than the version handshake.
+  IncompatibleBridge { expected: u32, found: Option<u32> },
+  /// The worker exited, or didn't report that it was ready within the boot timeout
+  BootFailed {
+    /// Exit status of the process, `None` if it was still running when the boot timed out
+    status: Option<ExitStatus>,
+    /// Last lines printed by the process on stdout and stderr
+    output: Vec<String>,
+  },
+  /// Workers kept failing to boot, so the pool stopped spawning them for a while (see `WorkerPool::set_circuit_breaker`)
+  PoolUnavailable {
+    /// Number of consecutive boot failures
+    failures: u32,
+    /// Time left before the pool tries to spawn a worker again
+    retry_in: Duration,
+  },
+  /// The worker was killed while performing the task because it exceeded one of its limits (see `WorkerPool::set_resource_limits`)
+  ResourceLimitExceeded {
+    resource: Resource,
+    status: Option<ExitStatus>,
+    /// Last lines printed by the process on stdout and stderr
+    output: Vec<String>,
+  },
+  /// The node binary doesn't satisfy the version required by the pool (see `WorkerPool::require_node_version`)
+  UnsupportedNode {
+    binary: String,
+    found: String,
+    required: String,
+  },
+  /// The worker script doesn't define the task
+  TaskNotFound {
+    command: String,
+    available: Vec<String>,
+  },
+  /// The result of the task couldn't be deserialized into the requested type
+  Deserialize {
+    /// Command of the task, unknown for handles created with `WorkerThread::from_handle`
+    command: Option<String>,
+    /// Id of the worker that performed the task
+    worker: Option<usize>,
+    /// Path of the value that failed to deserialize, such as `users[0].name`
+    path: String,
+    message: String,
+    /// Result of the task, truncated if it's too long
+    raw: String,
+  },
+}
+
+impl fmt::Display for WorkerError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      WorkerError::QueueFull { capacity } => {
+        write!(f, "task queue is full (capacity: {})", capacity)
+      }
+      WorkerError::Dropped => write!(f, "task was dropped from the queue"),
+      WorkerError::Cancelled => write!(f, "task was cancelled"),
+      WorkerError::PoolClosed => write!(f, "pool was dropped"),
+      WorkerError::IncompatibleBridge { expected, found } => match found {
+        Some(found) => write!(
+          f,
+          "worker bridge uses protocol version {} but version {} is required",
+          found, expected
+        ),
+        None => write!(
+          f,
+          "worker bridge doesn't report its protocol version, version {} is required",
+          expected
+        ),
+      },
+      WorkerError::BootFailed { status, output } => {
+        match status {
+          Some(status) => write!(f, "worker exited before being ready ({})", status)?,
+          None => write!(f, "worker didn't get ready within the boot timeout")?,
+        }
+        if !output.is_empty() {
+          write!(f, "\nlast output of the worker:")?;
+          for line in output {
+            write!(f, "\n  {}", line)?;
+          }
+        }
+        Ok(())
+      }
+      WorkerError::PoolUnavailable { failures, retry_in } => write!(
+        f,
+        "pool is unavailable after {} consecutive boot failures of its workers, retrying in {:.1}s",
+        failures,
+        retry_in.as_secs_f64()
+      ),
+      WorkerError::ResourceLimitExceeded {
+        resource, status, ..
+      } => {
+        write!(f, "worker exceeded its {} limit", resource)?;
+        if let Some(status) = status {
+          write!(f, " ({})", status)?;
+        }
+        Ok(())
+      }
+      WorkerError::UnsupportedNode {
+        binary,
+        found,
+        required,
+      } => write!(
+        f,
+        "{} is node {}, but the pool requires node {}",
+        binary, found, required
+      ),
+      WorkerError::TaskNotFound { command, available } => write!(
+        f,
+        "task \"{}\" not found for this worker (available tasks: {})",
+        command,
+        available.join(", ")
+      ),
+      WorkerError::Deserialize {
+        command,
+        worker,
+        path,
+        message,
+        raw,
+      } => {
+        write!(f, "failed to deserialize result")?;
+        if let Some(command) = command {
+          write!(f, " of task \"{}\"", command)?;
+        }
+        if let Some(worker) = worker {
+          write!(f, " (worker {})", worker)?;
+        }
+        write!(f, " at `{}`: {} (received: {})", path, message, raw)
+      }
+    }
+  }
+}
+
+impl std::error::Error for WorkerError {}
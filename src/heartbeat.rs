@@ -0,0 +1,76 @@
+use std::{
+  sync::{Mutex, Weak},
+  time::Duration,
+};
+
+use anyhow::Result;
+
+use crate::{
+  print_debug,
+  rolling_restart::{boot, record_process, BootContext},
+  stats::{PoolMetrics, WorkerHealth},
+  worker::Worker,
+  worker_pool_inner::WorkerPoolInner,
+};
+
+/// Ping the idle workers of a pool every `interval`, and replace the ones that don't answer within `timeout`.
+/// A worker is locked while it's checked, so the pool can't give it a task before it was replaced.
+/// The maintenance thread stops once the pool is dropped.
+pub(crate) fn watch(inner: Weak<Mutex<WorkerPoolInner>>, interval: Duration, timeout: Duration) {
+  std::thread::spawn(move || loop {
+    std::thread::sleep(interval);
+    let inner = match inner.upgrade() {
+      Some(inner) => inner,
+      None => break,
+    };
+    // the pool is locked by the dispatcher while it looks for a worker, so it must not be locked while a worker is checked
+    let (workers, availability, metrics, context) = {
+      let mut pool = inner.lock().unwrap();
+      let context = BootContext::from_pool(&mut pool);
+      (
+        pool.workers.clone(),
+        pool.availability.clone(),
+        pool.metrics.clone(),
+        context,
+      )
+    };
+    for worker in workers {
+      // a busy worker is locked by the thread performing its task
+      if let std::result::Result::Ok(mut worker) = worker.try_lock() {
+        check(&mut worker, timeout, &metrics, &context);
+        drop(worker);
+        // the dispatcher skips idle workers while they're checked
+        availability.notify();
+      }
+    }
+  });
+}
+
+fn check(
+  worker: &mut Worker,
+  timeout: Duration,
+  metrics: &PoolMetrics,
+  context: &Result<BootContext>,
+) {
+  if !worker.idle || !worker.ready || worker.child.is_none() || !worker.supports_heartbeat() {
+    return;
+  }
+  if worker.ping(timeout).is_ok() {
+    metrics.worker_health(worker.id, WorkerHealth::Healthy);
+    return;
+  }
+  print_debug!(
+    worker.debug,
+    "[pool] (heartbeat) worker {} is unresponsive, replacing it",
+    worker.id
+  );
+  worker.terminate();
+  worker.health = WorkerHealth::Unresponsive;
+  metrics.worker_health(worker.id, WorkerHealth::Unresponsive);
+  // if the replacement doesn't boot, a process is spawned on next use instead
+  if let std::result::Result::Ok(context) = context {
+    if boot(context, worker).is_ok() {
+      record_process(metrics, worker);
+    }
+  }
+}
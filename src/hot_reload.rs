@@ -0,0 +1,128 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::{Mutex, Weak},
+  time::{Duration, SystemTime},
+};
+
+use anyhow::{Context, Result};
+
+use crate::{print_debug, worker_pool_inner::WorkerPoolInner};
+
+/// How often watched files are checked for changes
+const WATCH_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Extensions that may be left out of the worker path, including the ones of TypeScript loaders
+const EXTENSIONS: [&str; 6] = ["js", "mjs", "cjs", "ts", "mts", "cts"];
+
+/// Files node may load for a worker path, as it can be given without its extension or as a directory
+fn script_files(worker_path: &str) -> Vec<PathBuf> {
+  entry_file(Path::new(worker_path)).into_iter().collect()
+}
+
+/// The file at `path`, or with one of the `EXTENSIONS` appended
+fn with_extension(path: &Path) -> Option<PathBuf> {
+  if path.is_file() {
+    return Some(path.to_path_buf());
+  }
+  EXTENSIONS
+    .iter()
+    .map(|ext| {
+      let mut file = path.as_os_str().to_owned();
+      file.push(format!(".{}", ext));
+      PathBuf::from(file)
+    })
+    .find(|file| file.is_file())
+}
+
+/// Entry file of a worker, which is the `main` file of a package or its `index` file for a directory
+fn entry_file(worker_path: &Path) -> Option<PathBuf> {
+  if !worker_path.is_dir() {
+    return with_extension(worker_path);
+  }
+  std::fs::read_to_string(worker_path.join("package.json"))
+    .ok()
+    .and_then(|package| serde_json::from_str::<serde_json::Value>(&package).ok())
+    .and_then(|package| package["main"].as_str().map(|main| worker_path.join(main)))
+    .and_then(|main| entry_file(&main))
+    .or_else(|| with_extension(&worker_path.join("index")))
+}
+
+/// Files watched for changes, along with their last modification time
+fn snapshot(worker_path: &str, patterns: &[glob::Pattern]) -> HashMap<PathBuf, Option<SystemTime>> {
+  let mut files = script_files(worker_path);
+  for pattern in patterns {
+    if let std::result::Result::Ok(paths) = glob::glob(pattern.as_str()) {
+      files.extend(paths.flatten());
+    }
+  }
+  files
+    .into_iter()
+    .map(|path| {
+      let modified = path.metadata().and_then(|m| m.modified()).ok();
+      (path, modified)
+    })
+    .collect()
+}
+
+/// Watch the worker script of a pool, and the files matching `patterns`, and reload the pool when one of them changes.
+/// The watcher stops once the pool is dropped.
+pub(crate) fn watch(inner: Weak<Mutex<WorkerPoolInner>>, patterns: &[&str]) -> Result<()> {
+  let patterns = patterns
+    .iter()
+    .map(|pattern| glob::Pattern::new(pattern).with_context(|| format!("invalid glob {}", pattern)))
+    .collect::<Result<Vec<_>>>()?;
+  let (worker_path, debug) = {
+    let inner = inner.upgrade().context("pool was dropped")?;
+    let inner = inner.lock().unwrap();
+    (inner.worker_path.clone(), inner.debug)
+  };
+  let mut files = snapshot(&worker_path, &patterns);
+  print_debug!(debug, "[pool] watching {} files", files.len());
+
+  std::thread::spawn(move || loop {
+    std::thread::sleep(WATCH_INTERVAL);
+    let inner = match inner.upgrade() {
+      Some(inner) => inner,
+      None => break,
+    };
+    // the script may have been changed with `WorkerPool::set_worker_path`
+    let worker_path = inner.lock().unwrap().worker_path.clone();
+    let current = snapshot(&worker_path, &patterns);
+    if current != files {
+      files = current;
+      inner.lock().unwrap().reload();
+    }
+  });
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn entry_files() {
+    let dir = std::env::temp_dir().join(format!("node-workers-entry-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("package")).unwrap();
+    std::fs::create_dir_all(dir.join("index")).unwrap();
+    for file in [
+      "worker.v2.mjs",
+      "worker.ts",
+      "package/main.cjs",
+      "index/index.mts",
+    ] {
+      std::fs::write(dir.join(file), "").unwrap();
+    }
+    std::fs::write(dir.join("package/package.json"), r#"{ "main": "main" }"#).unwrap();
+    let entry = |path: &str| entry_file(&dir.join(path));
+
+    assert_eq!(entry("worker.ts"), Some(dir.join("worker.ts")));
+    assert_eq!(entry("worker"), Some(dir.join("worker.ts")));
+    assert_eq!(entry("worker.v2"), Some(dir.join("worker.v2.mjs")));
+    assert_eq!(entry("package"), Some(dir.join("package/main.cjs")));
+    assert_eq!(entry("index"), Some(dir.join("index/index.mts")));
+    assert_eq!(entry("missing"), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -1,12 +1,21 @@
-import readline from 'readline';
+import readline from 'node:readline';
 
-type Task = <T>(payload?: T) => object | null;
+interface TaskContext {
+  /** Aborted when the task is cancelled from Rust */
+  signal: AbortSignal;
+}
+type Task = (payload: any, context: TaskContext) => unknown | Promise<unknown>;
 type Tasks = Record<string, Task>;
 interface Options {
   debug?: boolean;
 }
 type Payload = {_inner_payload?: any};
 
+/** Version of the protocol spoken with the Rust crate, reported when the worker is ready */
+export const PROTOCOL_VERSION = 1;
+/** Optional capabilities of this bridge */
+const FEATURES = ["async", "cancel", "heartbeat"];
+
 export function bridge(tasks: Tasks, opts: Options = {}) {
   const rl = readline.createInterface({
     input: process.stdin,
@@ -22,6 +31,34 @@ export function bridge(tasks: Tasks, opts: Options = {}) {
   let payloadStr = "";
   let payload: Payload | null = null;
   let payloadStart: number | null = null;
+  let controller: AbortController | null = null;
+
+  async function runTask(task: Task) {
+    const current = new AbortController();
+    controller = current;
+    let res;
+    try {
+      res = await task(payload, { signal: current.signal });
+    } catch (err) {
+      if (!current.signal.aborted) throw err;
+    } finally {
+      controller = null;
+    }
+    if (current.signal.aborted) {
+      debug("task cancelled");
+      console.log("CANCELLED");
+      return;
+    }
+    if (res) {
+      const str = JSON.stringify(res);
+      const chunks = str.match(/.{1,1000}/g) || [];
+      for (const chunk of chunks) {
+        console.log(`RESULT_CHUNK: ${chunk}`);
+      }
+    }
+    console.log("OK");
+  }
+
   rl.on("line", function (line) {
     switch (line) {
       case "PAYLOAD_END":
@@ -35,6 +72,14 @@ export function bridge(tasks: Tasks, opts: Options = {}) {
         debug("payload :>> ", payload);
         console.log("PAYLOAD_OK");
         break;
+      case "CANCEL":
+        debug("cancelling task");
+        controller?.abort();
+        break;
+      case "PING":
+        // answered from the event loop, so a worker stuck in a synchronous loop doesn't reply
+        console.log("PONG");
+        break;
       default: {
         if (line.startsWith("PAYLOAD_CHUNK:")) {
           if (!payloadStart) {
@@ -45,23 +90,33 @@ export function bridge(tasks: Tasks, opts: Options = {}) {
           const cmd = line.replace("CMD:", "").trim();
           const task = tasks[cmd];
           if (!task) {
-            throw new Error(`Task "${cmd}" not found for this worker`);
+            debug(`task "${cmd}" not found`);
+            console.log("TASK_NOT_FOUND");
+            break;
           }
           debug("executing command: ", cmd);
-          const res = task?.(payload);
-          if (res) {
-            const str = JSON.stringify(res);
-            const chunks = str.match(/.{1,1000}/g) || [];
-            for (const chunk of chunks) {
-              console.log(`RESULT_CHUNK: ${chunk}`);
-            }
-          }
-          console.log("OK");
+          runTask(task).catch((err) => {
+            // a failing task crashes the worker, as if it threw synchronously
+            process.nextTick(() => {
+              throw err;
+            });
+          });
         }
         break;
       }
     }
   });
 
-  console.log("READY");
+  // stdin is closed when the Rust program exits, even if it was killed
+  rl.on("close", () => {
+    debug("stdin closed, exiting");
+    process.exit(0);
+  });
+
+  const handshake = {
+    version: PROTOCOL_VERSION,
+    features: FEATURES,
+    tasks: Object.keys(tasks),
+  };
+  console.log(`READY ${JSON.stringify(handshake)}`);
 }
@@ -53,11 +53,43 @@
 //! ```
 
 mod as_payload;
+mod bridge;
+mod circuit_breaker;
+mod error;
+mod heartbeat;
+mod hot_reload;
+mod managed_file;
+mod node_binary;
+mod perform_iter;
+mod process_limit;
+mod process_output;
+mod resource_limits;
+mod retry;
+mod rolling_restart;
+mod runtime;
+mod spawn;
+mod stats;
+mod task_queue;
+mod typescript;
 mod utils;
 mod worker;
+mod worker_manager;
 mod worker_pool;
 mod worker_pool_inner;
 mod worker_thread;
 
 pub use as_payload::*;
+pub use bridge::{BridgeInfo, PROTOCOL_VERSION};
+pub use circuit_breaker::CircuitBreakerPolicy;
+pub use error::*;
+pub use node_binary::NODE_BINARY_ENV;
+pub use perform_iter::PerformIter;
+pub use resource_limits::{Resource, ResourceLimits};
+pub use retry::{RetryOn, RetryPolicy};
+pub use rolling_restart::{RestartHandle, RestartProgress};
+pub use runtime::Runtime;
+pub use stats::*;
+pub use task_queue::{QueuePolicy, TaskOptions};
+pub use worker_manager::WorkerManager;
 pub use worker_pool::*;
+pub use worker_thread::{CancelHandle, WorkerThread};
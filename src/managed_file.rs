@@ -0,0 +1,101 @@
+use std::{
+  fs::OpenOptions,
+  io::{ErrorKind, Write},
+  path::{Path, PathBuf},
+  sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{Context, Result};
+
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A file written to the temporary directory for node to load, removed once dropped
+#[derive(Debug)]
+pub(crate) struct ManagedFile {
+  path: PathBuf,
+}
+
+impl ManagedFile {
+  /// Write `contents` to a new file. `name` is appended to the file name, which is unique to this process.
+  /// Files left behind by a previous process with the same pid are skipped.
+  pub fn new(name: &str, contents: &str) -> Result<Self> {
+    loop {
+      let path = file_path(NEXT_FILE_ID.fetch_add(1, Ordering::SeqCst), name);
+      match write_new(&path, contents) {
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+        res => {
+          res.with_context(|| format!("writing to {}", path.to_string_lossy()))?;
+          return Ok(ManagedFile { path });
+        }
+      }
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+fn file_path(id: usize, name: &str) -> PathBuf {
+  std::env::temp_dir().join(format!(
+    "node-workers-{}-{}-{}",
+    std::process::id(),
+    id,
+    name
+  ))
+}
+
+/// Create `path` readable by the current user only, failing if it already exists so that a file planted at the predictable
+/// path is never loaded
+fn write_new(path: &Path, contents: &str) -> std::io::Result<()> {
+  let mut options = OpenOptions::new();
+  options.write(true).create_new(true);
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.mode(0o600);
+  }
+  let mut file = options.open(path)?;
+  file.write_all(contents.as_bytes())
+}
+
+impl Drop for ManagedFile {
+  fn drop(&mut self) {
+    let _ = std::fs::remove_file(&self.path);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn existing_file() {
+    let path = std::env::temp_dir().join(format!("node-workers-{}-existing", std::process::id()));
+    std::fs::write(&path, "planted").unwrap();
+    assert!(write_new(&path, "contents").is_err());
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "planted");
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn left_behind_file() {
+    let planted = file_path(NEXT_FILE_ID.load(Ordering::SeqCst), "left-behind.js");
+    std::fs::write(&planted, "planted").unwrap();
+    let file = ManagedFile::new("left-behind.js", "contents").unwrap();
+    assert_ne!(file.path(), planted);
+    assert_eq!(std::fs::read_to_string(file.path()).unwrap(), "contents");
+    assert_eq!(std::fs::read_to_string(&planted).unwrap(), "planted");
+    std::fs::remove_file(&planted).unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn private_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let file = ManagedFile::new("private.js", "").unwrap();
+    let mode = std::fs::metadata(file.path()).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+  }
+}
@@ -0,0 +1,189 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  process::Command,
+  sync::{Mutex, OnceLock},
+};
+
+use anyhow::{bail, Context, Result};
+use semver::{Version, VersionReq};
+
+use crate::error::WorkerError;
+
+/// Environment variable giving the node binary to run workers with, overriding the discovery
+pub const NODE_BINARY_ENV: &str = "NODE_WORKERS_NODE";
+
+/// Files pinning the node version of a project, read by version managers
+const VERSION_FILES: [&str; 2] = [".nvmrc", ".node-version"];
+
+/// Version reported by each binary, so that `node --version` only runs once
+static VERSIONS: OnceLock<Mutex<HashMap<String, Version>>> = OnceLock::new();
+
+/// Find the node binary running the workers of a script:
+/// - the binary given by the `NODE_WORKERS_NODE` environment variable
+/// - the installation of the version pinned by a `.nvmrc` or `.node-version` file, looked up from the directory
+///   of the script and then from the working directory, in the directories of nvm, fnm, volta, asdf and n
+/// - `node` from the `PATH` otherwise, or if the pinned version isn't installed
+pub(crate) fn resolve(worker_path: &str) -> String {
+  if let Some(node) = std::env::var_os(NODE_BINARY_ENV).filter(|node| !node.is_empty()) {
+    return node.to_string_lossy().to_string();
+  }
+  pinned_version(worker_path)
+    .and_then(|requirement| installed(&requirement))
+    .map(|node| node.to_string_lossy().to_string())
+    .unwrap_or_else(|| "node".into())
+}
+
+/// Requirement matching the version pinned in the nearest version file, such as `=20.11` for `v20.11`.
+/// Aliases like `lts/*` aren't supported.
+fn pinned_version(worker_path: &str) -> Option<VersionReq> {
+  let script_dir = Path::new(worker_path)
+    .canonicalize()
+    .ok()
+    .and_then(|path| path.parent().map(Path::to_path_buf));
+  let dirs = script_dir.into_iter().chain(std::env::current_dir().ok());
+  let version = dirs
+    .flat_map(|dir| dir.ancestors().map(Path::to_path_buf).collect::<Vec<_>>())
+    .flat_map(|dir| VERSION_FILES.map(|file| dir.join(file)))
+    .find_map(|file| std::fs::read_to_string(file).ok())?;
+  let version = version.lines().next()?.trim();
+  VersionReq::parse(&format!("={}", version.trim_start_matches('v'))).ok()
+}
+
+/// Directories where version managers install node, each version being in a subdirectory named after it,
+/// along with the path of the binary within that subdirectory
+fn install_dirs() -> Vec<(PathBuf, &'static str)> {
+  let home = std::env::var_os("HOME").map(PathBuf::from);
+  let dir =
+    |var: &str, default: Option<PathBuf>| std::env::var_os(var).map(PathBuf::from).or(default);
+  let home_dir = |path: &str| home.as_ref().map(|home| home.join(path));
+  [
+    (
+      dir("NVM_DIR", home_dir(".nvm")).map(|dir| dir.join("versions/node")),
+      "bin/node",
+    ),
+    (
+      dir("FNM_DIR", home_dir(".local/share/fnm")).map(|dir| dir.join("node-versions")),
+      "installation/bin/node",
+    ),
+    (
+      home_dir(".fnm").map(|dir| dir.join("node-versions")),
+      "installation/bin/node",
+    ),
+    (
+      dir("VOLTA_HOME", home_dir(".volta")).map(|dir| dir.join("tools/image/node")),
+      "bin/node",
+    ),
+    (
+      dir("ASDF_DATA_DIR", home_dir(".asdf")).map(|dir| dir.join("installs/nodejs")),
+      "bin/node",
+    ),
+    (
+      dir("N_PREFIX", Some("/usr/local".into())).map(|dir| dir.join("n/versions/node")),
+      "bin/node",
+    ),
+  ]
+  .into_iter()
+  .filter_map(|(dir, binary)| Some((dir?, binary)))
+  .collect()
+}
+
+/// Latest installation of node matching the requirement
+fn installed(requirement: &VersionReq) -> Option<PathBuf> {
+  find_installed(install_dirs(), requirement)
+}
+
+fn find_installed(dirs: Vec<(PathBuf, &str)>, requirement: &VersionReq) -> Option<PathBuf> {
+  dirs
+    .into_iter()
+    .filter_map(|(dir, binary)| std::fs::read_dir(dir).ok().map(|entries| (entries, binary)))
+    .flat_map(|(entries, binary)| {
+      entries.flatten().filter_map(move |entry| {
+        let name = entry.file_name();
+        let version = Version::parse(name.to_str()?.trim_start_matches('v')).ok()?;
+        let node = entry.path().join(binary);
+        (requirement.matches(&version) && node.is_file()).then_some((version, node))
+      })
+    })
+    .max_by(|(a, _), (b, _)| a.cmp(b))
+    .map(|(_, node)| node)
+}
+
+/// Version of a node binary, given by `node --version`
+pub(crate) fn version(node: &str) -> Result<Version> {
+  let versions = VERSIONS.get_or_init(Default::default);
+  if let Some(version) = versions.lock().unwrap().get(node) {
+    return Ok(version.clone());
+  }
+  let output = Command::new(node)
+    .arg("--version")
+    .output()
+    .with_context(|| format!("running {} --version", node))?;
+  if !output.status.success() {
+    bail!("{} --version failed ({})", node, output.status);
+  }
+  let stdout = String::from_utf8_lossy(&output.stdout);
+  let version = Version::parse(stdout.trim().trim_start_matches('v'))
+    .with_context(|| format!("parsing the version of {}: {}", node, stdout.trim()))?;
+  versions
+    .lock()
+    .unwrap()
+    .insert(node.to_string(), version.clone());
+  Ok(version)
+}
+
+/// Fail if the version of a node binary doesn't satisfy a requirement
+pub(crate) fn check(node: &str, requirement: &VersionReq) -> Result<()> {
+  let version = version(node)?;
+  if !requirement.matches(&version) {
+    bail!(WorkerError::UnsupportedNode {
+      binary: node.to_string(),
+      found: version.to_string(),
+      required: requirement.to_string(),
+    });
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  pub fn pinned_version_file() {
+    let dir = std::env::temp_dir().join(format!("node-workers-pinned-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("js")).unwrap();
+    std::fs::write(dir.join("js/worker.js"), "").unwrap();
+    // found in a parent of the directory of the script
+    std::fs::write(dir.join(".nvmrc"), "v18.17\n").unwrap();
+    let script = dir.join("js/worker.js");
+    let requirement = pinned_version(script.to_str().unwrap()).unwrap();
+    assert_eq!(requirement, VersionReq::parse("=18.17").unwrap());
+
+    // aliases of version managers aren't supported
+    std::fs::write(dir.join(".nvmrc"), "lts/*\n").unwrap();
+    assert_eq!(pinned_version(script.to_str().unwrap()), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  pub fn latest_installed_version() {
+    let dir = std::env::temp_dir().join(format!("node-workers-nvm-{}", std::process::id()));
+    for version in ["v18.17.0", "v18.17.1", "v20.0.0"] {
+      std::fs::create_dir_all(dir.join(version).join("bin")).unwrap();
+      std::fs::write(dir.join(version).join("bin/node"), "").unwrap();
+    }
+    // not an installation
+    std::fs::create_dir_all(dir.join("v18.18.0")).unwrap();
+    let find = |requirement: &str| {
+      find_installed(
+        vec![(dir.clone(), "bin/node")],
+        &VersionReq::parse(requirement).unwrap(),
+      )
+    };
+    assert_eq!(find("=18"), Some(dir.join("v18.17.1/bin/node")));
+    assert_eq!(find("=18.17.0"), Some(dir.join("v18.17.0/bin/node")));
+    assert_eq!(find("=16"), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}
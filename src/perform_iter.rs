@@ -0,0 +1,107 @@
+use std::{collections::VecDeque, marker::PhantomData, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::{
+  bridge::SharedBridgeInfo,
+  circuit_breaker::CircuitBreaker,
+  task_queue::{TaskOptions, TaskQueue},
+  worker_pool::{check_submission, new_task},
+  worker_thread::WorkerThread,
+};
+
+/// Iterator over the results of a batch of tasks, in completion order. Obtained with `WorkerPool::perform_iter`.
+///
+/// Each item is the index of the payload along with the result of its task.
+pub struct PerformIter<T> {
+  queue: Arc<TaskQueue>,
+  breaker: Arc<CircuitBreaker>,
+  info: SharedBridgeInfo,
+  cancel_timeout: Duration,
+  cmd: String,
+  pending: VecDeque<(usize, Value)>,
+  /// Index of the payload of each running task
+  indices: Vec<usize>,
+  running: Vec<WorkerThread>,
+  result: PhantomData<T>,
+}
+
+impl<T> PerformIter<T> {
+  pub(crate) fn new(
+    queue: Arc<TaskQueue>,
+    breaker: Arc<CircuitBreaker>,
+    info: SharedBridgeInfo,
+    cancel_timeout: Duration,
+    cmd: &str,
+    payloads: Vec<Value>,
+  ) -> Self {
+    PerformIter {
+      queue,
+      breaker,
+      info,
+      cancel_timeout,
+      cmd: cmd.to_string(),
+      pending: payloads.into_iter().enumerate().collect(),
+      indices: Vec::new(),
+      running: Vec::new(),
+      result: PhantomData,
+    }
+  }
+
+  /// Submit pending payloads as long as the queue has room for them
+  fn submit(&mut self) {
+    while let Some((n, payload)) = self.pending.pop_front() {
+      let (task, thread) = new_task(
+        &self.queue,
+        self.cancel_timeout,
+        &self.cmd,
+        payload,
+        TaskOptions::default(),
+      );
+      if let Err(error) = check_submission(&self.breaker, &self.info, &self.cmd) {
+        task.reject(error);
+        self.indices.push(n);
+        self.running.push(thread);
+        continue;
+      }
+      match self.queue.offer(task) {
+        Ok(()) => {
+          self.indices.push(n);
+          self.running.push(thread);
+        }
+        Err(task) => {
+          // nothing would make room in the queue if no task of ours is running, so honor the queue policy
+          if self.running.is_empty() {
+            let _ = self.queue.push(task, true);
+            self.indices.push(n);
+            self.running.push(thread);
+          } else {
+            self.pending.push_front((n, task.payload));
+          }
+          return;
+        }
+      }
+    }
+  }
+}
+
+impl<T: DeserializeOwned> Iterator for PerformIter<T> {
+  type Item = (usize, Result<Option<T>>);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    self.submit();
+    let n = WorkerThread::wait_any(&mut self.running)?;
+    let index = self.indices.swap_remove(n);
+    let thread = self.running.swap_remove(n);
+    Some((index, thread.get_result::<T>()))
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    let len = self.pending.len() + self.running.len();
+    (len, Some(len))
+  }
+}
+
+impl<T: DeserializeOwned> ExactSizeIterator for PerformIter<T> {}
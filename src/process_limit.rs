@@ -0,0 +1,85 @@
+use std::{
+  sync::{Arc, Condvar, Mutex, Weak},
+  time::Duration,
+};
+
+use crate::worker::Worker;
+
+/// How long to wait for a process to be released before looking for an idle worker to evict again
+const EVICTION_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Default)]
+struct LimitState {
+  spawned: usize,
+  workers: Vec<Weak<Mutex<Worker>>>,
+}
+
+/// Number of node processes that can run at the same time, shared between the pools of a `WorkerManager`.
+///
+/// When the limit is reached, a worker that needs a process kills the process of an idle worker,
+/// possibly from another pool. That worker spawns a new process the next time it's used.
+#[derive(Default)]
+pub(crate) struct ProcessLimit {
+  max: usize,
+  state: Mutex<LimitState>,
+  released: Condvar,
+}
+
+impl ProcessLimit {
+  pub fn new(max: usize) -> Self {
+    ProcessLimit {
+      max,
+      ..Default::default()
+    }
+  }
+
+  /// Number of processes currently running
+  pub fn spawned(&self) -> usize {
+    self.state.lock().unwrap().spawned
+  }
+
+  /// Make a worker's process a candidate for eviction when it's idle
+  pub fn register(&self, worker: &Arc<Mutex<Worker>>) {
+    let mut state = self.state.lock().unwrap();
+    state.workers.retain(|w| w.strong_count() > 0);
+    state.workers.push(Arc::downgrade(worker));
+  }
+
+  /// Block until a process can be spawned, evicting idle workers if needed
+  pub fn acquire(&self) {
+    loop {
+      let workers = {
+        let mut state = self.state.lock().unwrap();
+        if state.spawned < self.max {
+          state.spawned += 1;
+          return;
+        }
+        state.workers.clone()
+      };
+      // workers are only locked if they're free, so that this can't deadlock with a worker waiting for a process
+      let evicted = workers.iter().filter_map(|w| w.upgrade()).any(|w| {
+        if let Ok(mut w) = w.try_lock() {
+          if w.idle && w.child.is_some() {
+            w.terminate();
+            return true;
+          }
+        }
+        false
+      });
+      if !evicted {
+        let state = self.state.lock().unwrap();
+        let _ = self
+          .released
+          .wait_timeout_while(state, EVICTION_INTERVAL, |state| state.spawned >= self.max)
+          .unwrap();
+      }
+    }
+  }
+
+  /// Give back the slot of a process that exited
+  pub fn release(&self) {
+    let mut state = self.state.lock().unwrap();
+    state.spawned = state.spawned.saturating_sub(1);
+    self.released.notify_one();
+  }
+}
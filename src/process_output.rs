@@ -0,0 +1,119 @@
+use std::{
+  collections::VecDeque,
+  io::{BufRead, BufReader, Read},
+  process::{ChildStderr, ChildStdout},
+  sync::{
+    mpsc::{channel, Receiver, RecvTimeoutError},
+    Arc, Mutex,
+  },
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
+
+/// Number of lines of output kept to diagnose a worker that failed
+const KEPT_LINES: usize = 20;
+
+/// How long to wait for the remaining stderr of a process that exited
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_millis(200);
+
+/// Outcome of `ProcessOutput::next_line`
+pub(crate) enum ReadLine {
+  Line(String),
+  /// stdout was closed, usually because the process exited
+  Closed,
+  TimedOut,
+}
+
+/// Output of a worker process, read by background threads so that waiting for a line can time out.
+/// The last lines of stdout and stderr are kept for diagnostics, and stderr is forwarded to the stderr of the program.
+pub(crate) struct ProcessOutput {
+  lines: Receiver<String>,
+  recent: Arc<Mutex<VecDeque<String>>>,
+  stderr: Option<JoinHandle<()>>,
+}
+
+fn keep(recent: &Mutex<VecDeque<String>>, line: String) {
+  let mut recent = recent.lock().unwrap();
+  if recent.len() == KEPT_LINES {
+    recent.pop_front();
+  }
+  recent.push_back(line);
+}
+
+/// Call `f` with every line of `reader` until it's closed
+fn read_lines<R: Read>(reader: R, mut f: impl FnMut(String) -> bool) {
+  let mut reader = BufReader::new(reader);
+  loop {
+    let mut ln = String::new();
+    match reader.read_line(&mut ln) {
+      Ok(0) | Err(_) => break,
+      Ok(_) => {
+        if !f(ln) {
+          break;
+        }
+      }
+    }
+  }
+}
+
+impl ProcessOutput {
+  pub fn capture(stdout: ChildStdout, stderr: Option<ChildStderr>) -> Self {
+    let recent = Arc::new(Mutex::new(VecDeque::new()));
+    let (sender, lines) = channel();
+    std::thread::spawn({
+      let recent = recent.clone();
+      move || {
+        read_lines(stdout, |ln| {
+          if !ln.trim().is_empty() {
+            keep(&recent, format!("(stdout) {}", ln.trim_end()));
+          }
+          sender.send(ln).is_ok()
+        })
+      }
+    });
+    let stderr = stderr.map(|stderr| {
+      let recent = recent.clone();
+      std::thread::spawn(move || {
+        read_lines(stderr, |ln| {
+          eprint!("{}", ln);
+          keep(&recent, format!("(stderr) {}", ln.trim_end()));
+          true
+        })
+      })
+    });
+    ProcessOutput {
+      lines,
+      recent,
+      stderr,
+    }
+  }
+
+  /// Wait for the next line of stdout, until `deadline` if there's one
+  pub fn next_line(&self, deadline: Option<Instant>) -> ReadLine {
+    let res = match deadline {
+      Some(deadline) => self
+        .lines
+        .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+      None => self
+        .lines
+        .recv()
+        .map_err(|_| RecvTimeoutError::Disconnected),
+    };
+    match res {
+      Ok(ln) => ReadLine::Line(ln),
+      Err(RecvTimeoutError::Disconnected) => ReadLine::Closed,
+      Err(RecvTimeoutError::Timeout) => ReadLine::TimedOut,
+    }
+  }
+
+  /// Last lines printed by the process. Call it once the process exited to make sure its stderr was read entirely.
+  pub fn recent(&self) -> Vec<String> {
+    if let Some(stderr) = &self.stderr {
+      let start = Instant::now();
+      while !stderr.is_finished() && start.elapsed() < STDERR_DRAIN_TIMEOUT {
+        std::thread::sleep(Duration::from_millis(5));
+      }
+    }
+    self.recent.lock().unwrap().iter().cloned().collect()
+  }
+}
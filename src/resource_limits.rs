@@ -0,0 +1,121 @@
+use std::{
+  fmt,
+  process::{Command, ExitStatus},
+  time::Duration,
+};
+
+/// Limits on the resources of worker processes, configured with `WorkerPool::set_resource_limits`.
+/// Except for the heap size, limits are set with `setrlimit` before the worker is executed, so they're only supported on Unix.
+/// ```
+/// use node_workers::ResourceLimits;
+/// use std::time::Duration;
+///
+/// let limits = ResourceLimits {
+///   max_heap_mb: Some(256),
+///   cpu_time: Some(Duration::from_secs(60)),
+///   max_core_dump: Some(0),
+///   ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+  /// Size of the JS heap in megabytes, given to node and deno with `--max-old-space-size`. Not supported by bun.
+  pub max_heap_mb: Option<u64>,
+  /// Maximum size of the virtual memory of the process, in bytes (`RLIMIT_AS`).
+  /// Node reserves a lot of virtual memory, so prefer `max_heap_mb` to cap the memory of JS code.
+  pub address_space: Option<u64>,
+  /// CPU time the process can use over its lifetime, rounded up to the second (`RLIMIT_CPU`)
+  pub cpu_time: Option<Duration>,
+  /// Maximum number of file descriptors the process can open (`RLIMIT_NOFILE`)
+  pub open_files: Option<u64>,
+  /// Maximum size of core dumps, in bytes (`RLIMIT_CORE`). `Some(0)` disables them.
+  pub max_core_dump: Option<u64>,
+}
+
+/// Resource whose limit was exceeded by a worker, see `WorkerError::ResourceLimitExceeded`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resource {
+  /// The JS heap, or the virtual memory of the process
+  Memory,
+  CpuTime,
+}
+
+impl fmt::Display for Resource {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Resource::Memory => write!(f, "memory"),
+      Resource::CpuTime => write!(f, "CPU time"),
+    }
+  }
+}
+
+impl ResourceLimits {
+  /// Apply the limits to the process spawned by `command`
+  pub(crate) fn configure(&self, command: &mut Command) {
+    #[cfg(unix)]
+    {
+      use std::os::unix::process::CommandExt;
+
+      let limits = [
+        (libc::RLIMIT_AS, self.address_space, self.address_space),
+        // the process receives SIGXCPU when it reaches the soft limit, and SIGKILL at the hard limit
+        (
+          libc::RLIMIT_CPU,
+          self.cpu_time.map(cpu_seconds),
+          self.cpu_time.map(|time| cpu_seconds(time) + 1),
+        ),
+        (libc::RLIMIT_NOFILE, self.open_files, self.open_files),
+        (libc::RLIMIT_CORE, self.max_core_dump, self.max_core_dump),
+      ];
+      let limits = limits
+        .into_iter()
+        .filter_map(|(resource, soft, hard)| Some((resource, soft?, hard?)))
+        .collect::<Vec<_>>();
+      if limits.is_empty() {
+        return;
+      }
+      // SAFETY: setrlimit is async-signal-safe, and the limits are collected before forking
+      unsafe {
+        command.pre_exec(move || {
+          for (resource, soft, hard) in &limits {
+            let limit = libc::rlimit {
+              rlim_cur: *soft as libc::rlim_t,
+              rlim_max: *hard as libc::rlim_t,
+            };
+            if libc::setrlimit(*resource, &limit) == -1 {
+              return Err(std::io::Error::last_os_error());
+            }
+          }
+          Ok(())
+        });
+      }
+    }
+    #[cfg(not(unix))]
+    let _ = command;
+  }
+}
+
+#[cfg(unix)]
+fn cpu_seconds(time: Duration) -> u64 {
+  let secs = time.as_secs() + u64::from(time.subsec_nanos() > 0);
+  secs.max(1)
+}
+
+/// Find out whether a process that exited was killed for exceeding one of its limits,
+/// from its exit status and its last lines of output
+pub(crate) fn exceeded(status: Option<ExitStatus>, output: &[String]) -> Option<Resource> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::ExitStatusExt;
+    if status.and_then(|status| status.signal()) == Some(libc::SIGXCPU) {
+      return Some(Resource::CpuTime);
+    }
+  }
+  #[cfg(not(unix))]
+  let _ = status;
+  // printed by V8 when the heap is full, or when it can't reserve memory
+  output
+    .iter()
+    .any(|line| line.contains("out of memory"))
+    .then_some(Resource::Memory)
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+use anyhow::Error;
+
+use crate::error::WorkerError;
+
+/// Failures of a task that can be retried, see `RetryPolicy`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryOn {
+  /// The worker failed to boot, so the task never reached the worker script
+  BootFailed,
+  /// The worker exited while performing the task, because it crashed, ran out of memory, exceeded its resource limits
+  /// or the task threw
+  WorkerExited,
+}
+
+/// How the pool retries tasks that failed because of their worker, configured with `WorkerPool::set_retry_policy`.
+/// Tasks are retried on a fresh process of the same worker, after an exponential backoff.
+///
+/// A task that reached a worker may have had side effects before it failed, so it's only retried after
+/// `RetryOn::WorkerExited` if its command is listed in `idempotent`.
+/// ```
+/// use node_workers::{RetryOn, RetryPolicy};
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy {
+///   max_attempts: 3,
+///   backoff: Duration::from_millis(50),
+///   idempotent: vec!["fib2".into()],
+///   ..Default::default()
+/// };
+/// assert_eq!(policy.retry_on, vec![RetryOn::BootFailed, RetryOn::WorkerExited]);
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+  /// Maximum number of times a task is performed, including the first attempt. Defaults to 1, which disables retries.
+  pub max_attempts: u32,
+  /// Delay before the first retry, doubled after each attempt. Defaults to 100 milliseconds.
+  pub backoff: Duration,
+  /// Upper bound of the delay between two attempts. Defaults to 10 seconds.
+  pub max_backoff: Duration,
+  /// Failures that trigger a retry. Defaults to every kind of failure.
+  pub retry_on: Vec<RetryOn>,
+  /// Commands that can safely be performed more than once
+  pub idempotent: Vec<String>,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    RetryPolicy {
+      max_attempts: 1,
+      backoff: Duration::from_millis(100),
+      max_backoff: Duration::from_secs(10),
+      retry_on: vec![RetryOn::BootFailed, RetryOn::WorkerExited],
+      idempotent: Vec::new(),
+    }
+  }
+}
+
+impl RetryPolicy {
+  /// Delay before attempting `cmd` again after its attempt number `attempt` failed with `err`,
+  /// or `None` if it shouldn't be retried. `spawned` tells whether the worker had a process when the attempt failed.
+  pub(crate) fn retry_delay(
+    &self,
+    cmd: &str,
+    attempt: u32,
+    err: &Error,
+    spawned: bool,
+  ) -> Option<Duration> {
+    if attempt >= self.max_attempts {
+      return None;
+    }
+    let kind = match err.downcast_ref::<WorkerError>() {
+      Some(WorkerError::BootFailed { .. }) => RetryOn::BootFailed,
+      // the worker was killed, like when it runs out of memory on its own
+      Some(WorkerError::ResourceLimitExceeded { .. }) => RetryOn::WorkerExited,
+      Some(_) => return None,
+      // without a process, the binary couldn't be executed, which won't get better by retrying
+      None if !spawned => return None,
+      None => RetryOn::WorkerExited,
+    };
+    if !self.retry_on.contains(&kind) {
+      return None;
+    }
+    if kind == RetryOn::WorkerExited && !self.idempotent.iter().any(|c| c == cmd) {
+      return None;
+    }
+    let factor = 2u32.saturating_pow(attempt - 1);
+    Some(self.backoff.saturating_mul(factor).min(self.max_backoff))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use anyhow::anyhow;
+
+  use super::*;
+  use crate::resource_limits::Resource;
+
+  #[test]
+  fn retried_failures() {
+    let policy = RetryPolicy {
+      max_attempts: 3,
+      idempotent: vec!["fib2".into()],
+      ..Default::default()
+    };
+    let exceeded = Error::from(WorkerError::ResourceLimitExceeded {
+      resource: Resource::Memory,
+      status: None,
+      output: Vec::new(),
+    });
+    let exited = anyhow!("worker exited");
+
+    assert_eq!(
+      policy.retry_delay("fib2", 1, &exceeded, true),
+      Some(Duration::from_millis(100))
+    );
+    assert_eq!(
+      policy.retry_delay("fib2", 2, &exited, true),
+      Some(Duration::from_millis(200))
+    );
+    assert_eq!(policy.retry_delay("fib2", 3, &exited, true), None);
+    assert_eq!(policy.retry_delay("other", 1, &exceeded, true), None);
+    assert_eq!(policy.retry_delay("fib2", 1, &exited, false), None);
+    let cancelled = Error::from(WorkerError::Cancelled);
+    assert_eq!(policy.retry_delay("fib2", 1, &cancelled, true), None);
+  }
+}
@@ -0,0 +1,201 @@
+use std::{
+  sync::{atomic::Ordering, Arc, Mutex},
+  thread::JoinHandle,
+  time::Instant,
+};
+
+use anyhow::{bail, Result};
+
+use crate::{print_debug, stats::PoolMetrics, worker::Worker, worker_pool_inner::WorkerPoolInner};
+
+/// State of a rolling restart, see `WorkerPool::restart_all`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RestartProgress {
+  /// Number of workers to replace
+  pub total: usize,
+  /// Number of workers replaced so far
+  pub replaced: usize,
+  /// Workers whose replacement failed to boot, with the reason. They keep running their previous process.
+  pub errors: Vec<(usize, String)>,
+  pub finished: bool,
+}
+
+/// Handle on a rolling restart running in the background
+pub struct RestartHandle {
+  progress: Arc<Mutex<RestartProgress>>,
+  thread: JoinHandle<()>,
+}
+
+impl RestartHandle {
+  /// Get the current progress of the restart
+  pub fn progress(&self) -> RestartProgress {
+    self.progress.lock().unwrap().clone()
+  }
+
+  /// Check whether every worker was handled, without blocking
+  pub fn is_finished(&self) -> bool {
+    self.progress.lock().unwrap().finished
+  }
+
+  /// Wait for the restart to finish.
+  ///
+  /// ## Errors
+  ///
+  /// Fails if the replacement of a worker couldn't be booted.
+  pub fn join(self) -> Result<RestartProgress> {
+    if self.thread.join().is_err() {
+      bail!("thread panicked");
+    }
+    let progress = self.progress.lock().unwrap().clone();
+    if let Some((id, err)) = progress.errors.first() {
+      bail!("failed to restart worker {}: {}", id, err);
+    }
+    Ok(progress)
+  }
+}
+
+/// Replace the processes of the pool one at a time, in a new thread.
+/// The replacement of a worker is booted next to its current process, which keeps serving tasks until the new one is ready.
+pub(crate) fn restart(inner: Arc<Mutex<WorkerPoolInner>>, min_ready: usize) -> RestartHandle {
+  let progress = Arc::new(Mutex::new(RestartProgress::default()));
+  let thread = std::thread::spawn({
+    let progress = progress.clone();
+    move || {
+      let started_at = Instant::now();
+      let (workers, fresh, availability) = {
+        let mut pool = inner.lock().unwrap();
+        let workers = pool.workers.clone();
+        // busy workers count as running, as they're locked while performing a task
+        let running = workers
+          .iter()
+          .filter(|w| w.try_lock().map(|w| w.child.is_some()).unwrap_or(true))
+          .count();
+        let missing = min_ready
+          .saturating_sub(running)
+          .min(pool.max_workers - workers.len());
+        let fresh = (0..missing)
+          .map(|_| {
+            let worker = pool.new_worker();
+            let id = worker.lock().unwrap().id;
+            (id, worker)
+          })
+          .collect::<Vec<_>>();
+        (workers, fresh, pool.availability.clone())
+      };
+      progress.lock().unwrap().total = workers.len() + fresh.len();
+
+      for (id, worker) in fresh {
+        let res = BootContext::new(&inner).and_then(|context| {
+          let mut worker = worker.lock().unwrap();
+          boot(&context, &mut worker)?;
+          record_process(&context.metrics, &worker);
+          Ok(())
+        });
+        // the dispatcher skips fresh workers while they're booting
+        availability.notify();
+        record(&progress, id, res);
+      }
+      // workers are numbered from 1 in the order they were created
+      for (id, worker) in (1..).zip(workers) {
+        let res = replace(&inner, id, &worker, started_at);
+        availability.notify();
+        record(&progress, id, res);
+      }
+      progress.lock().unwrap().finished = true;
+    }
+  });
+  RestartHandle { progress, thread }
+}
+
+fn record(progress: &Mutex<RestartProgress>, id: usize, res: Result<()>) {
+  let mut progress = progress.lock().unwrap();
+  match res {
+    Ok(()) => progress.replaced += 1,
+    Err(err) => progress.errors.push((id, format!("{:#}", err))),
+  }
+}
+
+/// What's needed to boot the process of a worker, taken from the pool beforehand.
+/// Threads performing tasks lock their worker and then the pool, so the pool must not be locked while holding a worker.
+pub(crate) struct BootContext {
+  binary_args: Arc<Vec<String>>,
+  file_path: Arc<str>,
+  generation: usize,
+  pub metrics: Arc<PoolMetrics>,
+}
+
+impl BootContext {
+  pub fn new(inner: &Mutex<WorkerPoolInner>) -> Result<Self> {
+    Self::from_pool(&mut inner.lock().unwrap())
+  }
+
+  pub fn from_pool(pool: &mut WorkerPoolInner) -> Result<Self> {
+    Ok(BootContext {
+      binary_args: pool.spawn_args()?,
+      file_path: pool.worker_path.clone(),
+      generation: pool.generation.load(Ordering::SeqCst),
+      metrics: pool.metrics.clone(),
+    })
+  }
+}
+
+/// Spawn the process of a worker that has none and wait for it to be ready.
+/// The process is only recorded in the pool metrics by `record_process`, once it's used by the pool.
+pub(crate) fn boot(context: &BootContext, worker: &mut Worker) -> Result<()> {
+  let res = worker
+    .init(context.binary_args.clone(), context.file_path.clone())
+    .and_then(|_| {
+      worker.generation = context.generation;
+      worker.wait_for_ready()
+    });
+  if res.is_err() {
+    worker.terminate();
+  }
+  res
+}
+
+pub(crate) fn record_process(metrics: &PoolMetrics, worker: &Worker) {
+  metrics.worker_spawned(worker.id, worker.pid());
+  metrics.worker_ready(worker.id);
+}
+
+/// Boot a new process for a worker, then swap it with the current one once the worker is done with its task.
+/// The replacement is booted without waiting for a busy worker, which is checked again before the swap.
+fn replace(
+  inner: &Mutex<WorkerPoolInner>,
+  id: usize,
+  worker: &Mutex<Worker>,
+  started_at: Instant,
+) -> Result<()> {
+  let outdated = |worker: &Worker| worker.spawned_at.is_some_and(|at| at < started_at);
+  // a busy worker is locked by the thread performing its task
+  if worker.try_lock().is_ok_and(|worker| !outdated(&worker)) {
+    // the process will be spawned, or was spawned, with the current script
+    return Ok(());
+  }
+  let (context, mut replacement) = {
+    let mut pool = inner.lock().unwrap();
+    (BootContext::from_pool(&mut pool)?, pool.detached_worker(id))
+  };
+  let debug = replacement.debug;
+  print_debug!(
+    debug,
+    "[pool] (restart) booting replacement of worker {}",
+    id
+  );
+  boot(&context, &mut replacement)?;
+
+  let mut worker = worker.lock().unwrap();
+  if !outdated(&worker) {
+    // the process exited or was respawned while the replacement was booting
+    drop(worker);
+    replacement.terminate();
+    return Ok(());
+  }
+  worker.swap_process(&mut replacement);
+  record_process(&context.metrics, &worker);
+  drop(worker);
+  replacement.terminate();
+  print_debug!(debug, "[pool] (restart) worker {} replaced", id);
+  Ok(())
+}
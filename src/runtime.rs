@@ -0,0 +1,79 @@
+use std::path::Path;
+
+/// JS runtime running the workers, configured with `WorkerPool::set_runtime`.
+/// The runtime knows how to build the command line of a worker, and how to preload the bridge embedded in the crate.
+/// ```
+/// use node_workers::{Runtime, WorkerPool};
+///
+/// let mut pool = WorkerPool::setup("examples/worker.js", 2);
+/// pool.set_runtime(Runtime::Deno {
+///   permissions: vec!["--allow-read".into()],
+/// });
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Runtime {
+  /// `node`, the default. The binary is discovered as described in `WorkerPool::setup`.
+  #[default]
+  Node,
+  /// `deno run`, which requires Deno 2.4 or later to preload the bridge
+  Deno {
+    /// Permission flags given to `deno run`, such as `--allow-read`. Workers don't need any permission to talk to the pool.
+    permissions: Vec<String>,
+  },
+  /// `bun`
+  Bun,
+  /// Any other command, given the worker script as its last argument.
+  /// The bridge isn't preloaded, so the worker script must import it.
+  Custom(String),
+}
+
+impl Runtime {
+  /// Command running the runtime, which can be changed with `WorkerPool::set_binary`
+  pub(crate) fn binary(&self) -> Vec<String> {
+    match self {
+      Runtime::Node => vec!["node".into()],
+      Runtime::Deno { .. } => vec!["deno".into()],
+      Runtime::Bun => vec!["bun".into()],
+      Runtime::Custom(command) => shell_words::split(command).expect("couldn't parse binary"),
+    }
+  }
+
+  /// Arguments following the binary, before the worker script
+  pub(crate) fn args(&self, preload: Option<&Path>, max_heap_mb: Option<u64>) -> Vec<String> {
+    let preload = preload.map(|path| path.to_string_lossy().to_string());
+    let mut args = Vec::new();
+    match self {
+      Runtime::Node => {
+        if let Some(preload) = preload {
+          args.extend(["-r".into(), preload]);
+        }
+        if let Some(mb) = max_heap_mb {
+          args.push(format!("--max-old-space-size={}", mb));
+        }
+      }
+      Runtime::Deno { permissions } => {
+        args.push("run".into());
+        args.extend(permissions.iter().cloned());
+        if let Some(preload) = preload {
+          args.extend(["--preload".into(), preload]);
+        }
+        if let Some(mb) = max_heap_mb {
+          args.push(format!("--v8-flags=--max-old-space-size={}", mb));
+        }
+      }
+      // Bun runs on JavaScriptCore, which doesn't have an equivalent of `--max-old-space-size`
+      Runtime::Bun => {
+        if let Some(preload) = preload {
+          args.extend(["--preload".into(), preload]);
+        }
+      }
+      Runtime::Custom(_) => {}
+    }
+    args
+  }
+
+  /// Whether the runtime can preload the embedded bridge
+  pub(crate) fn preloads_bridge(&self) -> bool {
+    !matches!(self, Runtime::Custom(_))
+  }
+}
@@ -0,0 +1,120 @@
+use std::{
+  io,
+  process::{Child, Command},
+  sync::{
+    mpsc::{channel, Sender},
+    Mutex, OnceLock,
+  },
+};
+
+type SpawnRequest = (Command, Sender<io::Result<Child>>);
+
+/// Thread spawning every worker process, see `spawn`
+static SPAWNER: OnceLock<Mutex<Sender<SpawnRequest>>> = OnceLock::new();
+
+/// Configure a command so that its process doesn't outlive the program.
+///
+/// The process gets its own process group, so that `kill` also reaches the processes it spawned.
+/// On Linux, it's also killed by the kernel as soon as the program dies, even with `SIGKILL`.
+fn configure(command: &mut Command) {
+  #[cfg(unix)]
+  {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+  }
+  #[cfg(target_os = "linux")]
+  {
+    use std::os::unix::process::CommandExt;
+    let parent = std::process::id() as libc::pid_t;
+    // SAFETY: only async-signal-safe functions are called between fork and exec
+    unsafe {
+      command.pre_exec(move || {
+        if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) == -1 {
+          return Err(io::Error::last_os_error());
+        }
+        // the program may have died before the signal was set up
+        if libc::getppid() != parent {
+          libc::_exit(1);
+        }
+        Ok(())
+      });
+    }
+  }
+}
+
+/// Spawn a worker process.
+///
+/// The parent death signal is sent when the thread that spawned the process exits, rather than the whole program.
+/// Processes are therefore spawned from a thread that lives as long as the program, as workers are often
+/// spawned from short-lived threads performing a task.
+pub(crate) fn spawn(mut command: Command) -> io::Result<Child> {
+  configure(&mut command);
+  let spawner = SPAWNER.get_or_init(|| {
+    let (sender, receiver) = channel::<SpawnRequest>();
+    std::thread::spawn(move || {
+      for (mut command, reply) in receiver {
+        let _ = reply.send(command.spawn());
+      }
+    });
+    Mutex::new(sender)
+  });
+  let (reply, child) = channel();
+  spawner
+    .lock()
+    .unwrap()
+    .send((command, reply))
+    .map_err(|_| io::Error::other("spawner thread stopped"))?;
+  child
+    .recv()
+    .map_err(|_| io::Error::other("spawner thread stopped"))?
+}
+
+/// Check whether a process was waited for already. Its pid, and so its process group, may then belong to an unrelated process.
+#[cfg(unix)]
+fn reaped(child: &Child) -> bool {
+  // SAFETY: `WNOWAIT` leaves the process waitable, and `info` is a valid pointer
+  let res = unsafe {
+    let mut info: libc::siginfo_t = std::mem::zeroed();
+    libc::waitid(
+      libc::P_PID,
+      child.id() as libc::id_t,
+      &mut info,
+      libc::WEXITED | libc::WNOHANG | libc::WNOWAIT,
+    )
+  };
+  res == -1 && io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD)
+}
+
+/// Kill a process along with the processes it spawned, which belong to its process group.
+/// Nothing is killed once the process was waited for.
+pub(crate) fn kill(child: &mut Child) -> io::Result<()> {
+  #[cfg(unix)]
+  {
+    if reaped(child) {
+      return Ok(());
+    }
+    // SAFETY: the pid of the process identifies its process group, which lives on while the processes it spawned are running
+    if unsafe { libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL) } == 0 {
+      return Ok(());
+    }
+  }
+  child.kill()
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reaped_process() {
+    let mut command = Command::new("sleep");
+    command.arg("10");
+    let mut child = spawn(command).unwrap();
+    assert!(!reaped(&child));
+    kill(&mut child).unwrap();
+    child.wait().unwrap();
+    assert!(reaped(&child));
+    // the process group isn't signaled anymore
+    kill(&mut child).unwrap();
+  }
+}
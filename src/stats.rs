@@ -0,0 +1,423 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::Mutex,
+  time::{Duration, Instant},
+};
+
+/// Upper bounds (in seconds) of the buckets used by every histogram of the pool.
+/// These are the default buckets used by Prometheus client libraries.
+pub const HISTOGRAM_BUCKETS: [f64; 11] = [
+  0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Outcome of the latest heartbeat of a worker, see `WorkerPool::enable_heartbeat`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WorkerHealth {
+  /// The worker wasn't checked since its process was spawned
+  #[default]
+  Unknown,
+  /// The worker answered its latest heartbeat
+  Healthy,
+  /// The worker didn't answer its latest heartbeat in time, and its process was replaced
+  Unresponsive,
+}
+
+/// Snapshot of the state of a single worker
+#[derive(Debug, Clone, PartialEq)]
+pub struct WorkerStats {
+  pub id: usize,
+  /// Process id of the node binary, if it has been spawned
+  pub pid: Option<u32>,
+  /// Whether the worker printed `READY`
+  pub ready: bool,
+  pub idle: bool,
+  /// Number of tasks that completed successfully on this worker
+  pub tasks_done: u64,
+  pub failures: u64,
+  pub last_task_duration: Option<Duration>,
+  /// Time elapsed since the node binary was spawned
+  pub uptime: Option<Duration>,
+  pub health: WorkerHealth,
+}
+
+/// A histogram of durations, using `HISTOGRAM_BUCKETS` as bucket boundaries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+  /// Cumulative count of observations for each bucket of `HISTOGRAM_BUCKETS`
+  pub buckets: Vec<u64>,
+  pub count: u64,
+  pub sum: Duration,
+}
+
+impl Histogram {
+  pub fn new() -> Histogram {
+    Histogram {
+      buckets: vec![0; HISTOGRAM_BUCKETS.len()],
+      count: 0,
+      sum: Duration::ZERO,
+    }
+  }
+
+  /// Record a new observation
+  pub fn observe(&mut self, value: Duration) {
+    let secs = value.as_secs_f64();
+    for (n, bound) in HISTOGRAM_BUCKETS.iter().enumerate() {
+      if secs <= *bound {
+        self.buckets[n] += 1;
+      }
+    }
+    self.count += 1;
+    self.sum += value;
+  }
+
+  /// Average of all observations, if any
+  pub fn mean(&self) -> Option<Duration> {
+    if self.count == 0 {
+      None
+    } else {
+      Some(Duration::from_secs_f64(
+        self.sum.as_secs_f64() / self.count as f64,
+      ))
+    }
+  }
+}
+
+impl Default for Histogram {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Snapshot of the state of a pool, returned by `WorkerPool::stats`
+#[derive(Debug, Clone, PartialEq)]
+pub struct PoolStats {
+  pub max_workers: usize,
+  /// Number of workers currently performing a task
+  pub busy_workers: usize,
+  /// Number of tasks waiting in the queue of the pool
+  pub queued_tasks: usize,
+  pub workers: Vec<WorkerStats>,
+  pub tasks_completed: u64,
+  pub tasks_failed: u64,
+  /// Time tasks spent waiting for a worker to be available
+  pub queue_wait: Histogram,
+  /// Time spent performing tasks on a worker, by command
+  pub task_latency: HashMap<String, Histogram>,
+}
+
+#[cfg(feature = "prometheus")]
+impl PoolStats {
+  /// Render the stats using the Prometheus text exposition format.
+  /// ```
+  /// use node_workers::{EmptyPayload, WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// pool.perform::<(), _>("ping", EmptyPayload::bulk(1))?;
+  /// let metrics = pool.stats().to_prometheus();
+  /// assert!(metrics.contains("node_workers_task_duration_seconds_count{command=\"ping\"} 1"));
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn to_prometheus(&self) -> String {
+    use std::fmt::Write;
+
+    fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+      writeln!(out, "# HELP {} {}", name, help).unwrap();
+      writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+    }
+    fn histogram(out: &mut String, name: &str, labels: &str, histogram: &Histogram) {
+      let sep = if labels.is_empty() { "" } else { "," };
+      for (n, bound) in HISTOGRAM_BUCKETS.iter().enumerate() {
+        writeln!(
+          out,
+          "{}_bucket{{{}{}le=\"{}\"}} {}",
+          name, labels, sep, bound, histogram.buckets[n]
+        )
+        .unwrap();
+      }
+      writeln!(
+        out,
+        "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+        name, labels, sep, histogram.count
+      )
+      .unwrap();
+      let labels = if labels.is_empty() {
+        String::new()
+      } else {
+        format!("{{{}}}", labels)
+      };
+      writeln!(
+        out,
+        "{}_sum{} {}",
+        name,
+        labels,
+        histogram.sum.as_secs_f64()
+      )
+      .unwrap();
+      writeln!(out, "{}_count{} {}", name, labels, histogram.count).unwrap();
+    }
+
+    let mut out = String::new();
+    header(
+      &mut out,
+      "node_workers_max_workers",
+      "gauge",
+      "Maximum number of workers of the pool",
+    );
+    writeln!(out, "node_workers_max_workers {}", self.max_workers).unwrap();
+    header(
+      &mut out,
+      "node_workers_workers",
+      "gauge",
+      "Number of workers created by the pool",
+    );
+    writeln!(out, "node_workers_workers {}", self.workers.len()).unwrap();
+    header(
+      &mut out,
+      "node_workers_busy_workers",
+      "gauge",
+      "Number of workers performing a task",
+    );
+    writeln!(out, "node_workers_busy_workers {}", self.busy_workers).unwrap();
+    header(
+      &mut out,
+      "node_workers_queued_tasks",
+      "gauge",
+      "Number of tasks waiting for a worker",
+    );
+    writeln!(out, "node_workers_queued_tasks {}", self.queued_tasks).unwrap();
+    header(
+      &mut out,
+      "node_workers_tasks_total",
+      "counter",
+      "Number of tasks performed by the pool",
+    );
+    writeln!(
+      out,
+      "node_workers_tasks_total{{status=\"completed\"}} {}",
+      self.tasks_completed
+    )
+    .unwrap();
+    writeln!(
+      out,
+      "node_workers_tasks_total{{status=\"failed\"}} {}",
+      self.tasks_failed
+    )
+    .unwrap();
+
+    header(
+      &mut out,
+      "node_workers_worker_tasks_total",
+      "counter",
+      "Number of tasks completed by each worker",
+    );
+    for worker in &self.workers {
+      writeln!(
+        out,
+        "node_workers_worker_tasks_total{{worker=\"{}\"}} {}",
+        worker.id, worker.tasks_done
+      )
+      .unwrap();
+    }
+    header(
+      &mut out,
+      "node_workers_worker_failures_total",
+      "counter",
+      "Number of tasks that failed on each worker",
+    );
+    for worker in &self.workers {
+      writeln!(
+        out,
+        "node_workers_worker_failures_total{{worker=\"{}\"}} {}",
+        worker.id, worker.failures
+      )
+      .unwrap();
+    }
+    header(
+      &mut out,
+      "node_workers_worker_uptime_seconds",
+      "gauge",
+      "Time elapsed since each worker was spawned",
+    );
+    for worker in &self.workers {
+      if let Some(uptime) = worker.uptime {
+        writeln!(
+          out,
+          "node_workers_worker_uptime_seconds{{worker=\"{}\"}} {}",
+          worker.id,
+          uptime.as_secs_f64()
+        )
+        .unwrap();
+      }
+    }
+    header(
+      &mut out,
+      "node_workers_worker_healthy",
+      "gauge",
+      "Whether each worker answered its latest heartbeat",
+    );
+    for worker in &self.workers {
+      if worker.health != WorkerHealth::Unknown {
+        writeln!(
+          out,
+          "node_workers_worker_healthy{{worker=\"{}\"}} {}",
+          worker.id,
+          (worker.health == WorkerHealth::Healthy) as u8
+        )
+        .unwrap();
+      }
+    }
+
+    header(
+      &mut out,
+      "node_workers_queue_wait_seconds",
+      "histogram",
+      "Time tasks spent waiting for a worker",
+    );
+    histogram(
+      &mut out,
+      "node_workers_queue_wait_seconds",
+      "",
+      &self.queue_wait,
+    );
+    header(
+      &mut out,
+      "node_workers_task_duration_seconds",
+      "histogram",
+      "Time spent performing tasks, by command",
+    );
+    let mut commands = self.task_latency.keys().collect::<Vec<_>>();
+    commands.sort();
+    for cmd in commands {
+      histogram(
+        &mut out,
+        "node_workers_task_duration_seconds",
+        &format!(
+          "command=\"{}\"",
+          cmd.replace('\\', "\\\\").replace('"', "\\\"")
+        ),
+        &self.task_latency[cmd],
+      );
+    }
+    out
+  }
+}
+
+#[derive(Debug, Default)]
+struct WorkerEntry {
+  pid: Option<u32>,
+  spawned_at: Option<Instant>,
+  ready: bool,
+  busy: bool,
+  tasks_done: u64,
+  failures: u64,
+  last_task_duration: Option<Duration>,
+  health: WorkerHealth,
+}
+
+#[derive(Debug, Default)]
+struct MetricsInner {
+  workers: BTreeMap<usize, WorkerEntry>,
+  tasks_completed: u64,
+  tasks_failed: u64,
+  queue_wait: Histogram,
+  task_latency: HashMap<String, Histogram>,
+}
+
+/// Registry updated by the pool whenever a worker changes state.
+/// It lives outside of `WorkerPoolInner` so that stats can be read while the pool is waiting for a worker.
+#[derive(Debug, Default)]
+pub(crate) struct PoolMetrics {
+  inner: Mutex<MetricsInner>,
+}
+
+impl PoolMetrics {
+  pub fn worker_created(&self, id: usize) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.workers.entry(id).or_default();
+  }
+
+  pub fn worker_spawned(&self, id: usize, pid: Option<u32>) {
+    let mut inner = self.inner.lock().unwrap();
+    let entry = inner.workers.entry(id).or_default();
+    entry.pid = pid;
+    entry.spawned_at = Some(Instant::now());
+    entry.health = WorkerHealth::Unknown;
+  }
+
+  pub fn worker_health(&self, id: usize, health: WorkerHealth) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.workers.entry(id).or_default().health = health;
+  }
+
+  pub fn worker_ready(&self, id: usize) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.workers.entry(id).or_default().ready = true;
+  }
+
+  /// The process of a worker exited or was killed. The worker spawns a new one on next use.
+  pub fn worker_exited(&self, id: usize) {
+    let mut inner = self.inner.lock().unwrap();
+    let entry = inner.workers.entry(id).or_default();
+    entry.pid = None;
+    entry.spawned_at = None;
+    entry.ready = false;
+  }
+
+  pub fn task_started(&self, id: usize, queue_wait: Duration) {
+    let mut inner = self.inner.lock().unwrap();
+    inner.workers.entry(id).or_default().busy = true;
+    inner.queue_wait.observe(queue_wait);
+  }
+
+  pub fn task_finished(&self, id: usize, cmd: &str, duration: Duration, success: bool) {
+    let mut inner = self.inner.lock().unwrap();
+    let entry = inner.workers.entry(id).or_default();
+    entry.busy = false;
+    entry.last_task_duration = Some(duration);
+    if success {
+      entry.ready = true;
+      entry.tasks_done += 1;
+      inner.tasks_completed += 1;
+    } else {
+      entry.failures += 1;
+      inner.tasks_failed += 1;
+    }
+    inner
+      .task_latency
+      .entry(cmd.to_string())
+      .or_default()
+      .observe(duration);
+  }
+
+  pub fn snapshot(&self, max_workers: usize, queued_tasks: usize) -> PoolStats {
+    let inner = self.inner.lock().unwrap();
+    let workers = inner
+      .workers
+      .iter()
+      .map(|(id, entry)| WorkerStats {
+        id: *id,
+        pid: entry.pid,
+        ready: entry.ready,
+        idle: !entry.busy,
+        tasks_done: entry.tasks_done,
+        failures: entry.failures,
+        last_task_duration: entry.last_task_duration,
+        uptime: entry.spawned_at.map(|at| at.elapsed()),
+        health: entry.health,
+      })
+      .collect::<Vec<_>>();
+    PoolStats {
+      max_workers,
+      busy_workers: workers.iter().filter(|w| !w.idle).count(),
+      queued_tasks,
+      workers,
+      tasks_completed: inner.tasks_completed,
+      tasks_failed: inner.tasks_failed,
+      queue_wait: inner.queue_wait.clone(),
+      task_latency: inner.task_latency.clone(),
+    }
+  }
+}
@@ -0,0 +1,404 @@
+use anyhow::Result;
+use serde_json::Value;
+use std::{
+  collections::VecDeque,
+  sync::{
+    atomic::{AtomicU64, Ordering},
+    mpsc::Sender,
+    Arc, Condvar, Mutex,
+  },
+  time::{Duration, Instant},
+};
+
+use crate::{error::WorkerError, worker::ProcessControl, worker_thread::resolve};
+
+static NEXT_TASK_ID: AtomicU64 = AtomicU64::new(1);
+
+/// What to do when a task is submitted while the queue of the pool is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+  /// Block the caller until there is room in the queue
+  Block,
+  /// Reject the task with `WorkerError::QueueFull`
+  FailFast,
+  /// Remove the oldest queued task, which fails with `WorkerError::Dropped`, to make room for the new one
+  DropOldest,
+}
+
+/// Options of a task submitted with `WorkerPool::run_worker_with_options`
+/// ```
+/// use node_workers::TaskOptions;
+///
+/// let options = TaskOptions {
+///   priority: 10,
+///   ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskOptions {
+  /// Tasks with a higher priority are dispatched first. Defaults to 0.
+  pub priority: i32,
+}
+
+#[derive(Debug, Default)]
+struct ControlState {
+  cancelled: bool,
+  finished: bool,
+  process: Option<ProcessControl>,
+  worker: Option<usize>,
+  attempts: u32,
+}
+
+/// Outcome of `TaskControl::cancel`
+pub(crate) enum Cancellation {
+  /// The task hasn't been picked by a worker yet and must be removed from the queue
+  Queued,
+  /// The task is being performed by this process, which was sent the cancel frame
+  Running(ProcessControl),
+  /// The task already completed or was already cancelled
+  Noop,
+}
+
+/// State of a task shared between the pool and the handle returned to the user
+#[derive(Debug)]
+pub(crate) struct TaskControl {
+  pub id: u64,
+  pub cmd: String,
+  state: Mutex<ControlState>,
+  finished: Condvar,
+}
+
+impl TaskControl {
+  fn new(cmd: String) -> TaskControl {
+    TaskControl {
+      id: NEXT_TASK_ID.fetch_add(1, Ordering::SeqCst),
+      cmd,
+      state: Mutex::new(ControlState::default()),
+      finished: Condvar::new(),
+    }
+  }
+
+  /// Mark the task as running on a worker, once for every attempt. Returns false if the task was cancelled in the meantime.
+  pub fn start(&self, worker: usize, process: Option<ProcessControl>) -> bool {
+    let mut state = self.state.lock().unwrap();
+    state.worker = Some(worker);
+    state.process = process;
+    if !state.cancelled {
+      state.attempts += 1;
+    }
+    !state.cancelled
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.state.lock().unwrap().cancelled
+  }
+
+  /// Number of times the task was started on a worker
+  pub fn attempts(&self) -> u32 {
+    self.state.lock().unwrap().attempts
+  }
+
+  /// Mark the task as finished. Returns true if the task was cancelled.
+  pub fn finish(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    state.finished = true;
+    state.process = None;
+    self.finished.notify_all();
+    state.cancelled
+  }
+
+  /// Id of the worker the task was assigned to, if any
+  pub fn worker(&self) -> Option<usize> {
+    self.state.lock().unwrap().worker
+  }
+
+  /// Mark the task as cancelled. A running task is sent the cancel frame right away: the state stays locked while it's
+  /// written, so that the task can't finish in between and its worker abort the next task instead.
+  pub fn cancel(&self) -> Cancellation {
+    let mut state = self.state.lock().unwrap();
+    if state.cancelled || state.finished {
+      return Cancellation::Noop;
+    }
+    state.cancelled = true;
+    match &state.process {
+      Some(process) => {
+        let _ = process.send("CANCEL");
+        Cancellation::Running(process.clone())
+      }
+      None => Cancellation::Queued,
+    }
+  }
+
+  /// Wait for the task to finish. Returns false if it's still running after `timeout`.
+  pub fn wait_finished(&self, timeout: Duration) -> bool {
+    let state = self.state.lock().unwrap();
+    let (state, _) = self
+      .finished
+      .wait_timeout_while(state, timeout, |state| !state.finished)
+      .unwrap();
+    state.finished
+  }
+}
+
+/// A task waiting for a worker
+pub(crate) struct Task {
+  pub cmd: String,
+  pub payload: Value,
+  pub options: TaskOptions,
+  pub queued_at: Instant,
+  pub control: Arc<TaskControl>,
+  pub sender: Sender<Result<Option<String>>>,
+}
+
+impl Task {
+  pub fn with_options(
+    cmd: String,
+    payload: Value,
+    options: TaskOptions,
+    sender: Sender<Result<Option<String>>>,
+  ) -> Task {
+    Task {
+      control: Arc::new(TaskControl::new(cmd.clone())),
+      cmd,
+      payload,
+      options,
+      queued_at: Instant::now(),
+      sender,
+    }
+  }
+
+  /// Priority of the task, increased by one for every `aging` period spent in the queue
+  /// so that low priority tasks are not starved by a constant flow of higher priority ones.
+  fn effective_priority(&self, now: Instant, aging: Option<Duration>) -> i64 {
+    let priority = self.options.priority as i64;
+    match aging {
+      Some(aging) if !aging.is_zero() => {
+        let waited = now.saturating_duration_since(self.queued_at);
+        priority + (waited.as_nanos() / aging.as_nanos()) as i64
+      }
+      _ => priority,
+    }
+  }
+
+  /// Resolve the task with an error without running it
+  pub fn reject(self, error: WorkerError) {
+    resolve(&self.sender, Err(error.into()));
+  }
+}
+
+struct QueueState {
+  /// Tasks in submission order
+  tasks: VecDeque<Task>,
+  capacity: usize,
+  policy: QueuePolicy,
+  aging: Option<Duration>,
+  closed: bool,
+}
+
+/// Priority queue of tasks consumed by the dispatcher thread of the pool.
+/// Tasks of the same priority are dispatched in submission order.
+pub(crate) struct TaskQueue {
+  state: Mutex<QueueState>,
+  not_empty: Condvar,
+  not_full: Condvar,
+}
+
+impl TaskQueue {
+  pub fn new() -> TaskQueue {
+    TaskQueue {
+      state: Mutex::new(QueueState {
+        tasks: VecDeque::new(),
+        capacity: usize::MAX,
+        policy: QueuePolicy::Block,
+        aging: Some(Duration::from_secs(1)),
+        closed: false,
+      }),
+      not_empty: Condvar::new(),
+      not_full: Condvar::new(),
+    }
+  }
+
+  pub fn set_capacity(&self, capacity: usize) {
+    self.state.lock().unwrap().capacity = capacity;
+    self.not_full.notify_all();
+  }
+
+  pub fn set_policy(&self, policy: QueuePolicy) {
+    self.state.lock().unwrap().policy = policy;
+    self.not_full.notify_all();
+  }
+
+  pub fn set_aging(&self, aging: Option<Duration>) {
+    self.state.lock().unwrap().aging = aging;
+  }
+
+  pub fn len(&self) -> usize {
+    self.state.lock().unwrap().tasks.len()
+  }
+
+  /// Add a task to the queue, applying the queue policy if it's full.
+  /// When `blocking` is false, or when the capacity is 0 as nothing would ever make room,
+  /// `QueuePolicy::Block` behaves like `QueuePolicy::FailFast`.
+  /// If the task can't be queued, it is rejected.
+  pub fn push(&self, task: Task, blocking: bool) -> Result<(), WorkerError> {
+    let mut state = self.state.lock().unwrap();
+    loop {
+      if state.closed {
+        task.reject(WorkerError::PoolClosed);
+        return Err(WorkerError::PoolClosed);
+      }
+      if state.tasks.len() < state.capacity {
+        break;
+      }
+      let error = WorkerError::QueueFull {
+        capacity: state.capacity,
+      };
+      match state.policy {
+        QueuePolicy::Block if blocking && state.capacity > 0 => {
+          state = self.not_full.wait(state).unwrap();
+        }
+        QueuePolicy::DropOldest if !state.tasks.is_empty() => {
+          let oldest = state.tasks.pop_front().unwrap();
+          oldest.reject(WorkerError::Dropped);
+        }
+        _ => {
+          task.reject(error.clone());
+          return Err(error);
+        }
+      }
+    }
+    state.tasks.push_back(task);
+    self.not_empty.notify_one();
+    Ok(())
+  }
+
+  /// Add a task to the queue only if there's room for it, regardless of the queue policy.
+  /// The task is given back otherwise, unless the queue is closed in which case it's rejected.
+  pub fn offer(&self, task: Task) -> Result<(), Task> {
+    let mut state = self.state.lock().unwrap();
+    if state.closed {
+      task.reject(WorkerError::PoolClosed);
+      return Ok(());
+    }
+    if state.tasks.len() >= state.capacity {
+      return Err(task);
+    }
+    state.tasks.push_back(task);
+    self.not_empty.notify_one();
+    Ok(())
+  }
+
+  /// Block until a task is available. Returns false once the queue is closed and empty.
+  pub fn wait_for_task(&self) -> bool {
+    let mut state = self.state.lock().unwrap();
+    while state.tasks.is_empty() {
+      if state.closed {
+        return false;
+      }
+      state = self.not_empty.wait(state).unwrap();
+    }
+    true
+  }
+
+  /// Take the task with the highest priority, if any
+  pub fn pop(&self) -> Option<Task> {
+    let mut state = self.state.lock().unwrap();
+    let now = Instant::now();
+    let mut best: Option<(usize, i64)> = None;
+    for (n, task) in state.tasks.iter().enumerate() {
+      let priority = task.effective_priority(now, state.aging);
+      let higher = match best {
+        Some((_, best)) => priority > best,
+        None => true,
+      };
+      if higher {
+        best = Some((n, priority));
+      }
+    }
+    let task = best.and_then(|(n, _)| state.tasks.remove(n));
+    drop(state);
+    if task.is_some() {
+      self.not_full.notify_one();
+    }
+    task
+  }
+
+  /// Remove a task from the queue, if it's still there
+  pub fn remove(&self, id: u64) -> Option<Task> {
+    let mut state = self.state.lock().unwrap();
+    let n = state.tasks.iter().position(|task| task.control.id == id)?;
+    let task = state.tasks.remove(n);
+    drop(state);
+    self.not_full.notify_one();
+    task
+  }
+
+  /// Stop accepting tasks, which are rejected with `WorkerError::PoolClosed`. Tasks already queued will still be dispatched.
+  pub fn close(&self) {
+    self.state.lock().unwrap().closed = true;
+    self.not_empty.notify_all();
+    self.not_full.notify_all();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::{Task, TaskOptions, TaskQueue};
+  use crate::error::WorkerError;
+  use serde_json::Value;
+  use std::{sync::mpsc::channel, time::Duration};
+
+  fn task(cmd: &str, priority: i32) -> Task {
+    let (sender, _) = channel();
+    Task::with_options(cmd.into(), Value::Null, TaskOptions { priority }, sender)
+  }
+
+  #[test]
+  pub fn pop_by_priority() {
+    let queue = TaskQueue::new();
+    queue.push(task("a", 0), true).unwrap();
+    queue.push(task("b", 5), true).unwrap();
+    queue.push(task("c", 0), true).unwrap();
+    queue.push(task("d", 5), true).unwrap();
+    queue.push(task("e", -1), true).unwrap();
+
+    let order = std::iter::from_fn(|| queue.pop())
+      .map(|t| t.cmd)
+      .collect::<Vec<_>>();
+    assert_eq!(order, vec!["b", "d", "a", "c", "e"]);
+  }
+
+  #[test]
+  pub fn zero_capacity() {
+    let queue = TaskQueue::new();
+    queue.set_capacity(0);
+    let err = queue.push(task("a", 0), true).unwrap_err();
+    assert_eq!(err, WorkerError::QueueFull { capacity: 0 });
+  }
+
+  #[test]
+  pub fn closed_queue() {
+    let queue = TaskQueue::new();
+    queue.close();
+    let err = queue.push(task("a", 0), true).unwrap_err();
+    assert_eq!(err, WorkerError::PoolClosed);
+    assert!(queue.offer(task("b", 0)).is_ok());
+    assert_eq!(queue.len(), 0);
+  }
+
+  #[test]
+  pub fn aging_prevents_starvation() {
+    let queue = TaskQueue::new();
+    queue.set_aging(Some(Duration::from_millis(5)));
+    queue.push(task("low", 0), true).unwrap();
+    std::thread::sleep(Duration::from_millis(30));
+    queue.push(task("high", 2), true).unwrap();
+    assert_eq!(queue.pop().unwrap().cmd, "low");
+
+    queue.set_aging(None);
+    queue.push(task("low", 0), true).unwrap();
+    std::thread::sleep(Duration::from_millis(30));
+    queue.push(task("high", 2), true).unwrap();
+    assert_eq!(queue.pop().unwrap().cmd, "high");
+  }
+}
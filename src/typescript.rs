@@ -0,0 +1,112 @@
+use std::{path::Path, process::Command, sync::Arc};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// Flags of node that load code before the worker script, in which case a TypeScript loader was configured by the user
+const LOADER_FLAGS: [&str; 6] = [
+  "-r",
+  "--require",
+  "--import",
+  "--loader",
+  "--experimental-loader",
+  "--experimental-strip-types",
+];
+
+/// Whether node needs a loader to run the worker script
+pub(crate) fn is_typescript(worker_path: &str) -> bool {
+  matches!(
+    Path::new(worker_path)
+      .extension()
+      .and_then(|ext| ext.to_str()),
+    Some("ts" | "mts" | "cts")
+  )
+}
+
+/// Whether the command running node already loads something before the worker script
+pub(crate) fn has_loader(binary_args: &[String]) -> bool {
+  binary_args
+    .iter()
+    .any(|arg| LOADER_FLAGS.contains(&arg.split('=').next().unwrap_or(arg)))
+}
+
+/// Loader detected by `loader_args` for a node binary and a worker script
+#[derive(Debug)]
+pub(crate) struct DetectedLoader {
+  pub node: String,
+  pub worker_path: Arc<str>,
+  pub args: Vec<String>,
+}
+
+/// What the node binary reports about itself, see `PROBE`
+#[derive(Debug, Deserialize)]
+struct Probe {
+  version: String,
+  tsx: Option<String>,
+  #[serde(rename = "esbuildRegister")]
+  esbuild_register: Option<String>,
+}
+
+/// Print the version of node, and where the loaders resolve from the directory given as argument or the working directory
+const PROBE: &str = r#"
+const resolve = (name) => {
+  try {
+    return require.resolve(name, { paths: [process.argv[1], process.cwd()] });
+  } catch (err) {
+    return null;
+  }
+};
+console.log(JSON.stringify({
+  version: process.versions.node,
+  tsx: resolve("tsx"),
+  esbuildRegister: resolve("esbuild-register"),
+}));
+"#;
+
+fn parse_version(version: &str) -> (u32, u32) {
+  let mut parts = version.split('.').map(|n| n.parse().unwrap_or(0));
+  (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+/// Arguments letting `node` run a TypeScript worker script.
+/// Node strips types by default since 22.18 and 23.6, and behind a flag since 22.6.
+/// Older versions use tsx or esbuild-register, if they can be resolved from the script or the working directory.
+pub(crate) fn loader_args(node: &str, worker_path: &str) -> Result<Vec<String>> {
+  let dir = Path::new(worker_path)
+    .parent()
+    .filter(|dir| !dir.as_os_str().is_empty())
+    .unwrap_or(Path::new("."));
+  let output = Command::new(node)
+    .arg("-e")
+    .arg(PROBE)
+    .arg(dir)
+    .output()
+    .with_context(|| format!("running {} to detect a TypeScript loader", node))?;
+  let probe = serde_json::from_slice::<Probe>(&output.stdout)
+    .with_context(|| format!("detecting a TypeScript loader with {}", node))?;
+
+  let version = parse_version(&probe.version);
+  if version >= (23, 6) || (version.0 == 22 && version.1 >= 18) {
+    return Ok(Vec::new());
+  }
+  if version >= (22, 6) {
+    return Ok(vec!["--experimental-strip-types".into()]);
+  }
+  if let Some(tsx) = probe.tsx {
+    // `--import` expects a URL
+    let tsx = tsx.replace('\\', "/");
+    let slash = if tsx.starts_with('/') { "" } else { "/" };
+    return Ok(vec!["--import".into(), format!("file://{}{}", slash, tsx)]);
+  }
+  match probe.esbuild_register {
+    // esbuild-register only hooks into `require`, so it can't load ES modules
+    Some(esbuild_register) if !worker_path.ends_with(".mts") => {
+      Ok(vec!["-r".into(), esbuild_register])
+    }
+    _ => bail!(
+      "{} is a TypeScript worker, but node {} can't run TypeScript: upgrade to node 22.6 or later, or install tsx (npm install --save-dev tsx)",
+      worker_path,
+      probe.version
+    ),
+  }
+}
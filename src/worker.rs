@@ -1,21 +1,79 @@
 use anyhow::{bail, Context, Ok, Result};
 use serde_json::Value;
 use std::{
-  io::{BufRead, BufReader, Write},
-  process::{Child, ChildStdin, ChildStdout, Command, Stdio},
-  sync::Arc,
+  io::Write,
+  process::{Child, ChildStdin, Command, ExitStatus, Stdio},
+  sync::{Arc, Mutex},
+  time::{Duration, Instant},
 };
 
-use crate::print_debug;
+use crate::{
+  bridge::{self, BridgeInfo, SharedBridgeInfo},
+  circuit_breaker::CircuitBreaker,
+  error::WorkerError,
+  print_debug,
+  process_limit::ProcessLimit,
+  process_output::{ProcessOutput, ReadLine},
+  resource_limits::{self, ResourceLimits},
+  spawn,
+  stats::{PoolMetrics, WorkerHealth},
+};
+
+/// How long to wait for a process to exit after it closed its stdout
+const EXIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Handle on the process of a worker that can be used from another thread while a task is running
+#[derive(Debug, Clone)]
+pub struct ProcessControl {
+  stdin: Arc<Mutex<ChildStdin>>,
+  child: Arc<Mutex<Child>>,
+}
+
+impl ProcessControl {
+  /// Write a line to the process stdin
+  pub fn send(&self, line: &str) -> Result<()> {
+    self
+      .stdin
+      .lock()
+      .unwrap()
+      .write_all(format!("{}\n", line).as_bytes())
+      .context("writing to process stdin")
+  }
+
+  /// Kill the process and the processes it spawned. This unblocks any thread waiting on its stdout.
+  pub fn kill(&self) {
+    let _ = spawn::kill(&mut self.child.lock().unwrap());
+  }
+}
 
 pub struct Worker {
   pub id: usize,
-  pub child: Option<Child>,
-  pub stdout: Option<BufReader<ChildStdout>>,
-  pub stdin: Option<ChildStdin>,
+  pub child: Option<Arc<Mutex<Child>>>,
+  pub(crate) stdout: Option<ProcessOutput>,
+  pub stdin: Option<Arc<Mutex<ChildStdin>>>,
   pub idle: bool,
   pub ready: bool,
   pub debug: bool,
+  /// Generation of the pool when the process was spawned, see `WorkerPoolInner::reload`
+  pub generation: usize,
+  pub spawned_at: Option<Instant>,
+  /// How long the process has to print `READY` once spawned
+  pub boot_timeout: Option<Duration>,
+  /// What the bridge reported in its handshake
+  pub info: Option<BridgeInfo>,
+  /// Where the bridge info is published for the pool
+  pub(crate) pool_info: Option<SharedBridgeInfo>,
+  /// Limit shared with other pools on the number of running processes
+  pub(crate) limit: Option<Arc<ProcessLimit>>,
+  /// Circuit breaker of the pool, which stops spawning processes when they keep failing to boot
+  pub(crate) breaker: Option<Arc<CircuitBreaker>>,
+  /// Limits applied to the process when it's spawned, see `WorkerPool::set_resource_limits`
+  pub resources: Option<Arc<ResourceLimits>>,
+  /// Outcome of the latest heartbeat, see `ping`
+  pub health: WorkerHealth,
+  pub last_heartbeat: Option<Instant>,
+  /// Metrics of the pool, told when the process exits
+  pub(crate) metrics: Option<Arc<PoolMetrics>>,
 }
 
 impl Worker {
@@ -28,6 +86,17 @@ impl Worker {
       ready: false,
       idle: true,
       debug,
+      generation: 0,
+      spawned_at: None,
+      boot_timeout: None,
+      info: None,
+      pool_info: None,
+      limit: None,
+      breaker: None,
+      resources: None,
+      health: WorkerHealth::Unknown,
+      last_heartbeat: None,
+      metrics: None,
     }
   }
 
@@ -38,36 +107,109 @@ impl Worker {
     let bin = &binary_args[0];
     let mut args = binary_args[1..].to_vec();
     args.push(file_path.to_string());
-    let mut child = Command::new(bin)
+    if let Some(breaker) = &self.breaker {
+      breaker.acquire()?;
+    }
+    if let Some(limit) = &self.limit {
+      limit.acquire();
+    }
+    let mut command = Command::new(bin);
+    command
       .args(args)
       .stdin(Stdio::piped())
       .stdout(Stdio::piped())
-      .spawn()
-      .context("execute process")?;
-    self.stdin = Some(child.stdin.take().context("get process stdin")?);
-    self.stdout = Some(BufReader::new(
-      child.stdout.take().context("take process stdout")?,
+      .stderr(Stdio::piped());
+    if let Some(resources) = &self.resources {
+      resources.configure(&mut command);
+    }
+    let child = spawn::spawn(command);
+    let mut child = match child {
+      std::result::Result::Ok(child) => child,
+      Err(err) => {
+        if let Some(limit) = &self.limit {
+          limit.release();
+        }
+        if let Some(breaker) = &self.breaker {
+          breaker.record_failure();
+        }
+        return Err(err).context("execute process");
+      }
+    };
+    let stdin = child.stdin.take();
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    // keep the child before checking the pipes so that it's reaped by `terminate` on error
+    self.child = Some(Arc::new(Mutex::new(child)));
+    self.stdin = Some(Arc::new(Mutex::new(stdin.context("get process stdin")?)));
+    self.stdout = Some(ProcessOutput::capture(
+      stdout.context("take process stdout")?,
+      stderr,
     ));
+    self.spawned_at = Some(Instant::now());
+    self.health = WorkerHealth::Unknown;
+    self.last_heartbeat = None;
     print_debug!(self.debug, "[worker {}] child spawned", self.id);
-    self.child = Some(child);
     Ok(())
   }
 
+  /// Process id of the node binary, if it's spawned
+  pub fn pid(&self) -> Option<u32> {
+    self.child.as_ref().map(|child| child.lock().unwrap().id())
+  }
+
+  /// Get a handle that lets another thread write to the process or kill it
+  pub fn process(&self) -> Option<ProcessControl> {
+    Some(ProcessControl {
+      stdin: self.stdin.clone()?,
+      child: self.child.clone()?,
+    })
+  }
+
   pub fn perform_task(&mut self, cmd: String, payload: Value) -> Result<Option<String>> {
     self.idle = false;
 
-    let mut reader = self.stdout.take().unwrap();
-    let stdin = self.stdin.take().unwrap();
-    let mut child = self.child.take().unwrap();
+    self.wait_for_ready()?;
 
-    if !self.ready {
-      self
-        .communicate("", "READY", &stdin, &mut reader, &mut child)
-        .context("communicating with process")?;
-      self.ready = true;
+    print_debug!(self.debug, "[worker {}] is ready", self.id);
+    let res = self.send_task(cmd, payload);
+    if let Err(err) = &res {
+      if self.resources.is_some() && err.downcast_ref::<WorkerError>().is_none() {
+        if let Some(exceeded) = self.limit_exceeded() {
+          return Err(exceeded.into());
+        }
+      }
     }
+    res
+  }
 
-    print_debug!(self.debug, "[worker {}] is ready", self.id);
+  /// Check whether the process exited because it exceeded one of its resource limits
+  fn limit_exceeded(&self) -> Option<WorkerError> {
+    let status = self.exit_status();
+    let output = self.stdout.as_ref()?.recent();
+    let resource = resource_limits::exceeded(status, &output)?;
+    print_debug!(
+      self.debug,
+      "[worker {}] exceeded its {} limit",
+      self.id,
+      resource
+    );
+    Some(WorkerError::ResourceLimitExceeded {
+      resource,
+      status,
+      output,
+    })
+  }
+
+  /// Send the payload and the command of a task to the bridge, and wait for its result
+  fn send_task(&mut self, cmd: String, payload: Value) -> Result<Option<String>> {
+    if let Some(info) = &self.info {
+      if !info.tasks.contains(&cmd) {
+        bail!(WorkerError::TaskNotFound {
+          command: cmd,
+          available: info.tasks.clone(),
+        });
+      }
+    }
     if !payload.is_null() {
       let payload_str = payload.to_string();
       let chunks = payload_str
@@ -77,66 +219,180 @@ impl Worker {
         .collect::<Result<Vec<&str>, _>>()?;
       for chunk in chunks {
         self
-          .communicate(
-            &format!("PAYLOAD_CHUNK: {}", chunk),
-            "",
-            &stdin,
-            &mut reader,
-            &mut child,
-          )
+          .communicate(&format!("PAYLOAD_CHUNK: {}", chunk), "")
           .context("communicating with process")?;
       }
       self
-        .communicate("PAYLOAD_END", "PAYLOAD_OK", &stdin, &mut reader, &mut child)
+        .communicate("PAYLOAD_END", "PAYLOAD_OK")
         .context("communicating with process")?;
     }
     let result_str = self
-      .communicate(
-        &format!("CMD: {}", cmd),
-        "OK",
-        &stdin,
-        &mut reader,
-        &mut child,
-      )
+      .communicate(&format!("CMD: {}", cmd), "OK")
       .context("communicating with process")?;
 
-    self.stdout = Some(reader);
-    self.stdin = Some(stdin);
-    self.child = Some(child);
-
     print_debug!(self.debug, "[worker {}] task finished", self.id);
     self.idle = true;
 
     Ok(result_str)
   }
 
-  pub fn wait_for_ready(&mut self) -> Result<()> {
-    if !self.ready {
-      let mut reader = self.stdout.take().unwrap();
-      let stdin = self.stdin.take().unwrap();
-      let mut child = self.child.take().unwrap();
+  /// Exchange the processes of two workers
+  pub fn swap_process(&mut self, other: &mut Worker) {
+    std::mem::swap(&mut self.child, &mut other.child);
+    std::mem::swap(&mut self.stdin, &mut other.stdin);
+    std::mem::swap(&mut self.stdout, &mut other.stdout);
+    std::mem::swap(&mut self.ready, &mut other.ready);
+    std::mem::swap(&mut self.generation, &mut other.generation);
+    std::mem::swap(&mut self.spawned_at, &mut other.spawned_at);
+    std::mem::swap(&mut self.info, &mut other.info);
+    std::mem::swap(&mut self.health, &mut other.health);
+    std::mem::swap(&mut self.last_heartbeat, &mut other.last_heartbeat);
+  }
 
-      self
-        .communicate("", "READY", &stdin, &mut reader, &mut child)
-        .context("communicating with process")?;
-      self.ready = true;
+  /// Whether the bridge of the process answers heartbeats
+  pub fn supports_heartbeat(&self) -> bool {
+    self
+      .info
+      .as_ref()
+      .is_some_and(|info| info.features.iter().any(|f| f == "heartbeat"))
+  }
+
+  /// Check that the event loop of an idle process is responsive, by waiting for `PONG` in reply to `PING`.
+  /// Updates the health of the worker, and fails if the process didn't answer within `timeout`.
+  pub fn ping(&mut self, timeout: Duration) -> Result<()> {
+    let res = self
+      .communicate_until("PING", "PONG", Some(Instant::now() + timeout))
+      .map(|_| ());
+    self.last_heartbeat = Some(Instant::now());
+    self.health = if res.is_ok() {
+      WorkerHealth::Healthy
+    } else {
+      WorkerHealth::Unresponsive
+    };
+    res
+  }
+
+  /// Kill the process, if it's still running, and reset the worker so that a new process is spawned on next use
+  pub fn terminate(&mut self) {
+    if let Some(child) = self.child.take() {
+      let mut child = child.lock().unwrap();
+      let _ = spawn::kill(&mut child);
+      let _ = child.wait();
+      if let Some(limit) = &self.limit {
+        limit.release();
+      }
+      if let Some(metrics) = &self.metrics {
+        metrics.worker_exited(self.id);
+      }
+    }
+    self.stdin = None;
+    self.stdout = None;
+    self.spawned_at = None;
+    self.info = None;
+    self.ready = false;
+    self.idle = true;
+  }
 
-      self.stdout = Some(reader);
-      self.stdin = Some(stdin);
-      self.child = Some(child);
+  /// Wait for the handshake of the bridge, and check that it speaks the same protocol version.
+  /// Fails with `WorkerError::BootFailed` if the process exits or doesn't print `READY` within the boot timeout.
+  pub fn wait_for_ready(&mut self) -> Result<()> {
+    if self.ready {
+      return Ok(());
     }
+    let res = self.handshake();
+    if let Some(breaker) = &self.breaker {
+      if res.is_ok() {
+        breaker.record_success();
+      } else if breaker.record_failure() {
+        print_debug!(
+          self.debug,
+          "[worker {}] too many boot failures, opening the circuit",
+          self.id
+        );
+      }
+    }
+    res
+  }
+
+  /// Read the `READY` line of the bridge and check the protocol version it reports
+  fn handshake(&mut self) -> Result<()> {
+    let deadline = self
+      .spawned_at
+      .zip(self.boot_timeout)
+      .map(|(at, timeout)| at + timeout);
+    let handshake = match self.communicate_until("", "READY", deadline) {
+      std::result::Result::Ok(handshake) => handshake,
+      Err(err) => {
+        print_debug!(self.debug, "[worker {}] failed to boot: {}", self.id, err);
+        let timed_out = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+        return Err(self.boot_failure(timed_out).into());
+      }
+    };
+    let info = bridge::parse_handshake(handshake.as_deref())?;
+    print_debug!(
+      self.debug,
+      "[worker {}] bridge v{} with tasks {:?}",
+      self.id,
+      info.version,
+      info.tasks
+    );
+    if let Some(pool_info) = &self.pool_info {
+      *pool_info.lock().unwrap() = Some(info.clone());
+    }
+    self.info = Some(info);
+    self.ready = true;
     Ok(())
   }
 
-  pub fn communicate(
-    &self,
+  /// Describe why the process didn't boot. It's killed if it timed out.
+  fn boot_failure(&mut self, timed_out: bool) -> WorkerError {
+    let status = if timed_out {
+      if let Some(child) = &self.child {
+        let _ = spawn::kill(&mut child.lock().unwrap());
+      }
+      None
+    } else {
+      self.exit_status()
+    };
+    let output = self
+      .stdout
+      .as_ref()
+      .map(|stdout| stdout.recent())
+      .unwrap_or_default();
+    WorkerError::BootFailed { status, output }
+  }
+
+  /// Exit status of a process that closed its stdout, as it's exiting. It's given a moment to be reaped.
+  fn exit_status(&self) -> Option<ExitStatus> {
+    let child = self.child.as_ref()?;
+    let start = Instant::now();
+    loop {
+      if let std::result::Result::Ok(Some(status)) = child.lock().unwrap().try_wait() {
+        return Some(status);
+      }
+      if start.elapsed() >= EXIT_TIMEOUT {
+        return None;
+      }
+      std::thread::sleep(Duration::from_millis(5));
+    }
+  }
+
+  pub fn communicate(&mut self, send: &str, wait: &str) -> Result<Option<String>> {
+    self.communicate_until(send, wait, None)
+  }
+
+  /// Same as `communicate`, but fails if `wait` isn't received before `deadline`
+  fn communicate_until(
+    &mut self,
     send: &str,
     wait: &str,
-    mut stdin: &ChildStdin,
-    reader: &mut BufReader<ChildStdout>,
-    child: &mut Child,
+    deadline: Option<Instant>,
   ) -> Result<Option<String>> {
-    let status = child.try_wait()?;
+    let child = self.child.as_ref().context("process not spawned")?;
+    let stdin = self.stdin.as_ref().context("process not spawned")?;
+    let reader = self.stdout.as_ref().context("process not spawned")?;
+
+    let status = child.lock().unwrap().try_wait()?;
     if status.is_some() {
       bail!("process no longer running");
     }
@@ -148,6 +404,8 @@ impl Worker {
         send
       );
       stdin
+        .lock()
+        .unwrap()
         .write_all(format!("{}\n", send).as_bytes())
         .context("writing to process stdin")?;
     }
@@ -155,14 +413,15 @@ impl Worker {
       print_debug!(self.debug, "[worker {}] waiting for {}", self.id, wait);
       let mut payload_str = String::new();
       loop {
-        let status = child.try_wait()?;
+        let status = child.lock().unwrap().try_wait()?;
         if status.is_some() {
           bail!("process exited");
         }
-        let mut ln = String::new();
-        reader
-          .read_line(&mut ln)
-          .context("reading to process stdout")?;
+        let ln = match reader.next_line(deadline) {
+          ReadLine::Line(ln) => ln,
+          ReadLine::Closed => bail!("process exited"),
+          ReadLine::TimedOut => bail!("timed out waiting for {}", wait),
+        };
         if ln.trim().is_empty() {
           continue;
         }
@@ -179,6 +438,21 @@ impl Worker {
           } else {
             Ok(Some(payload_str))
           };
+        } else if let Some(data) = ln.strip_prefix("READY ").filter(|_| wait == "READY") {
+          // the handshake follows `READY`, other markers are whole lines so that tasks can print anything
+          print_debug!(self.debug, "[worker {}] {} received", self.id, wait);
+          return Ok(Some(data.trim().to_string()));
+        } else if ln == "TASK_NOT_FOUND\n" {
+          let command = send.trim_start_matches("CMD:").trim().to_string();
+          let available = self
+            .info
+            .as_ref()
+            .map(|info| info.tasks.clone())
+            .unwrap_or_default();
+          return Err(WorkerError::TaskNotFound { command, available }.into());
+        } else if ln == "CANCELLED\n" {
+          print_debug!(self.debug, "[worker {}] task cancelled", self.id);
+          return Err(WorkerError::Cancelled.into());
         } else if ln.starts_with("RESULT_CHUNK:") {
           print_debug!(self.debug, "[worker {}] received result chunk", self.id);
           payload_str += ln.replace("RESULT_CHUNK:", "").trim();
@@ -188,3 +462,10 @@ impl Worker {
     Ok(None)
   }
 }
+
+impl Drop for Worker {
+  /// Kill the process of a worker that's dropped along with its pool, giving back its slot in the process limit
+  fn drop(&mut self) {
+    self.terminate();
+  }
+}
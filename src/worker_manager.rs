@@ -0,0 +1,194 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use serde::de::DeserializeOwned;
+
+use crate::{
+  as_payload::AsPayload, process_limit::ProcessLimit, stats::PoolStats, worker_pool::WorkerPool,
+  worker_thread::WorkerThread,
+};
+
+/// Hosts multiple worker scripts, each with its own pool, under a global limit on the number of node processes.
+///
+/// Each script is registered with a name and a maximum number of workers. Once the global limit is reached,
+/// a script that needs a new process kills an idle process of any script to make room for it.
+/// ```
+/// use node_workers::WorkerManager;
+/// # use std::error::Error;
+///
+/// # fn main() -> Result<(), Box<dyn Error>> {
+/// let mut manager = WorkerManager::new(4);
+/// manager.add("tools", "examples/worker", 2);
+/// manager.add("ssr", "examples/worker", 4).with_debug(true);
+///
+/// let result = manager.run("tools", "fib2", 20u32)?.get_result::<u32>()?;
+/// println!("result: {:?}", result);
+/// # Ok(())
+/// # }
+/// ```
+pub struct WorkerManager {
+  pools: HashMap<String, WorkerPool>,
+  limit: Arc<ProcessLimit>,
+}
+
+impl WorkerManager {
+  /// Create a manager that runs at most `max_processes` node processes across all of its scripts
+  ///
+  /// ## Panics
+  ///
+  /// Panics if `max_processes` is 0, as no task could ever run.
+  pub fn new(max_processes: usize) -> Self {
+    assert!(max_processes > 0, "a WorkerManager needs at least 1 process");
+    WorkerManager {
+      pools: HashMap::new(),
+      limit: Arc::new(ProcessLimit::new(max_processes)),
+    }
+  }
+
+  /// Register a worker script under `name`, with at most `max_workers` processes running it.
+  /// Returns the pool of the script so that it can be configured. A script already registered under that name is replaced.
+  pub fn add(&mut self, name: &str, worker_path: &str, max_workers: usize) -> &mut WorkerPool {
+    let mut pool = WorkerPool::setup(worker_path, max_workers);
+    pool.set_process_limit(self.limit.clone());
+    self.pools.insert(name.to_string(), pool);
+    self.pools.get_mut(name).unwrap()
+  }
+
+  /// Get the pool of a script
+  pub fn pool(&mut self, name: &str) -> Option<&mut WorkerPool> {
+    self.pools.get_mut(name)
+  }
+
+  /// Names of the registered scripts
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.pools.keys().map(|name| name.as_str())
+  }
+
+  /// Run a task on a worker of the script `name`. Refer to `WorkerPool::run_worker` for details.
+  ///
+  /// ## Errors
+  ///
+  /// Fails if no script is registered under `name`.
+  pub fn run<P: AsPayload>(&mut self, name: &str, cmd: &str, payload: P) -> Result<WorkerThread> {
+    Ok(self.get(name)?.run_worker(cmd, payload))
+  }
+
+  /// Run a task for every payload on the workers of the script `name`. Refer to `WorkerPool::perform` for details.
+  pub fn perform<T: DeserializeOwned, P: AsPayload>(
+    &mut self,
+    name: &str,
+    cmd: &str,
+    payloads: Vec<P>,
+  ) -> Result<Vec<Option<T>>> {
+    self.get(name)?.perform(cmd, payloads)
+  }
+
+  /// Number of node processes currently running across all scripts
+  pub fn running_processes(&self) -> usize {
+    self.limit.spawned()
+  }
+
+  /// Get a snapshot of the state of every pool, by script name
+  pub fn stats(&self) -> HashMap<String, PoolStats> {
+    self
+      .pools
+      .iter()
+      .map(|(name, pool)| (name.clone(), pool.stats()))
+      .collect()
+  }
+
+  fn get(&mut self, name: &str) -> Result<&mut WorkerPool> {
+    self
+      .pools
+      .get_mut(name)
+      .with_context(|| format!("no worker script named \"{}\"", name))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::WorkerManager;
+
+  #[test]
+  #[should_panic(expected = "a WorkerManager needs at least 1 process")]
+  pub fn no_processes() {
+    WorkerManager::new(0);
+  }
+
+  #[test]
+  pub fn route_to_scripts() {
+    let mut manager = WorkerManager::new(2);
+    manager.add("a", "examples/worker", 2);
+    manager.add("b", "examples/worker", 2);
+    let mut names = manager.names().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["a", "b"]);
+
+    let result = manager.run("a", "fib2", 10).unwrap().get_result::<u32>();
+    assert_eq!(result.unwrap(), Some(89));
+    assert!(manager.run("c", "fib2", 10).is_err());
+  }
+
+  #[test]
+  pub fn global_process_limit() {
+    let mut manager = WorkerManager::new(2);
+    manager.add("a", "examples/worker", 2);
+    manager.add("b", "examples/worker", 2);
+
+    let results = manager
+      .perform::<u32, _>("a", "sleep", vec![200, 200])
+      .unwrap();
+    assert_eq!(results, vec![Some(200), Some(200)]);
+    assert_eq!(manager.running_processes(), 2);
+
+    // "b" evicts the idle processes of "a"
+    let threads = vec![
+      manager.run("b", "sleep", 200).unwrap(),
+      manager.run("b", "sleep", 200).unwrap(),
+    ];
+    let max = std::thread::spawn({
+      let limit = manager.limit.clone();
+      move || {
+        let mut max = 0;
+        for _ in 0..50 {
+          max = max.max(limit.spawned());
+          std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        max
+      }
+    });
+    for thread in threads {
+      assert_eq!(thread.get_result::<u32>().unwrap(), Some(200));
+    }
+    assert!(max.join().unwrap() <= 2);
+    assert_eq!(manager.running_processes(), 2);
+
+    // and "a" can still run tasks
+    let result = manager.run("a", "fib2", 10).unwrap().get_result::<u32>();
+    assert_eq!(result.unwrap(), Some(89));
+    assert!(manager.running_processes() <= 2);
+  }
+
+  #[test]
+  pub fn replaced_pool_releases_processes() {
+    let mut manager = WorkerManager::new(2);
+    manager.add("a", "examples/worker", 2);
+    let results = manager
+      .perform::<u32, _>("a", "sleep", vec![100, 100])
+      .unwrap();
+    assert_eq!(results, vec![Some(100), Some(100)]);
+    assert_eq!(manager.running_processes(), 2);
+
+    // the processes of the previous pool are killed once it's dropped
+    manager.add("a", "examples/worker", 2);
+    let start = std::time::Instant::now();
+    while manager.running_processes() > 0 {
+      assert!(start.elapsed() < std::time::Duration::from_secs(5));
+      std::thread::sleep(std::time::Duration::from_millis(10));
+    }
+    let results = manager
+      .perform::<u32, _>("a", "sleep", vec![100, 100])
+      .unwrap();
+    assert_eq!(results, vec![Some(100), Some(100)]);
+  }
+}
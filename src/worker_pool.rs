@@ -1,23 +1,65 @@
 use crate::{
-  as_payload::AsPayload, print_debug, worker_pool_inner::WorkerPoolInner,
-  worker_thread::WorkerThread,
+  as_payload::AsPayload,
+  bridge::{BridgeInfo, SharedBridgeInfo},
+  circuit_breaker::{CircuitBreaker, CircuitBreakerPolicy},
+  error::WorkerError,
+  heartbeat, hot_reload,
+  managed_file::ManagedFile,
+  perform_iter::PerformIter,
+  print_debug,
+  process_limit::ProcessLimit,
+  resource_limits::ResourceLimits,
+  retry::RetryPolicy,
+  rolling_restart::{self, RestartHandle},
+  runtime::Runtime,
+  stats::{PoolMetrics, PoolStats},
+  task_queue::{QueuePolicy, Task, TaskOptions, TaskQueue},
+  worker_pool_inner::{wait_for_worker, WorkerPoolInner},
+  worker_thread::{CancelHandle, WorkerThread},
 };
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use semver::VersionReq;
 use serde::de::DeserializeOwned;
+use serde_json::Value;
 use std::{
-  sync::{Arc, Mutex},
+  sync::{mpsc::channel, Arc, Mutex},
   thread::JoinHandle,
+  time::Duration,
 };
 
+/// How `WorkerPool::perform_settled` handles failing tasks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SettleMode {
+  /// Wait for every task to settle
+  #[default]
+  All,
+  /// Cancel the remaining tasks as soon as one fails
+  FailFast,
+}
+
 /// A pool of nodejs workers.
 /// Wraps a inner struct inside `Arc<Mutex<T>>` to be able to invoke it's method within a spawned thread.
 /// This is important so that indefinitely blocking methods such as `get_available_workers` can be offloaded.
+///
+/// Submitted tasks are pushed to a queue, which is consumed by a single dispatcher thread assigning tasks to idle workers.
 pub struct WorkerPool {
   inner: Arc<Mutex<WorkerPoolInner>>,
+  metrics: Arc<PoolMetrics>,
+  info: SharedBridgeInfo,
+  breaker: Arc<CircuitBreaker>,
+  queue: Arc<TaskQueue>,
+  dispatcher: Option<JoinHandle<()>>,
+  cancel_timeout: Duration,
+  min_ready_workers: usize,
+  max_workers: usize,
 }
 
 impl WorkerPool {
-  /// Create a new workers pool with the maximum numbers of workers that can be spawned for the duration of the program
+  /// Create a new workers pool with the maximum numbers of workers that can be spawned for the duration of the program.
+  ///
+  /// Workers run with the node binary given by the `NODE_WORKERS_NODE` environment variable if it's set. Otherwise, if a
+  /// `.nvmrc` or `.node-version` file is found in the directory of the script or of the working directory (or one of their parents),
+  /// the matching version installed by nvm, fnm, volta, asdf or n is used. `node` is looked up in the `PATH` as a last resort.
   /// ```
   /// use node_workers::{WorkerPool};
   ///
@@ -25,13 +67,50 @@ impl WorkerPool {
   /// let mut pool = WorkerPool::setup("worker.js", nbr_max_workers);
   /// ```
   pub fn setup(worker_path: &str, max_workers: usize) -> Self {
+    let inner = WorkerPoolInner::setup(worker_path, max_workers);
     WorkerPool {
-      inner: Arc::new(Mutex::new(WorkerPoolInner::setup(worker_path, max_workers))),
+      metrics: inner.metrics.clone(),
+      info: inner.info.clone(),
+      breaker: inner.breaker.clone(),
+      inner: Arc::new(Mutex::new(inner)),
+      queue: Arc::new(TaskQueue::new()),
+      dispatcher: None,
+      cancel_timeout: Duration::from_secs(5),
+      min_ready_workers: 0,
+      max_workers,
     }
   }
 
+  /// Create a pool running a worker script given as source code, for instance embedded with `include_str!`,
+  /// so that the program doesn't depend on the script being on disk.
+  ///
+  /// The source is written to a file in the temporary directory that's removed once the pool is dropped.
+  /// Relative `require` calls resolve from that directory, so the script should be bundled or use absolute paths.
+  /// The script can call the global `bridge` function, see `set_bridge_preload`.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let source = "bridge({ double: (n) => n * 2 });";
+  /// let mut pool = WorkerPool::from_source(source, 2)?;
+  /// let result = pool.run_worker("double", 21).get_result::<u32>()?;
+  /// assert_eq!(result, Some(42));
+  /// # Ok(())
+  /// # }
+  /// ```
+  /// ## Errors
+  ///
+  /// Fails if the script can't be written to the temporary directory.
+  pub fn from_source(source: &str, max_workers: usize) -> Result<Self> {
+    let file = ManagedFile::new("worker.js", source)?;
+    let pool = WorkerPool::setup(&file.path().to_string_lossy(), max_workers);
+    pool.inner.lock().unwrap().source = Some(file);
+    Ok(pool)
+  }
+
   /// Configure the binary that's used to run JS workers
-  /// This can be usefull configure node or to run JS via another runtime
+  /// This can be usefull configure node, or to use a specific installation of the runtime (see `set_runtime`)
   /// ```rust
   /// use node_workers::{EmptyPayload, WorkerPool};
   /// # use std::error::Error;
@@ -47,11 +126,239 @@ impl WorkerPool {
     self.inner.lock().unwrap().set_binary(binary);
   }
 
+  /// Set the JS runtime running the workers. Defaults to `Runtime::Node`.
+  /// This resets the binary to the default command of the runtime, which can then be changed with `set_binary`.
+  /// ```
+  /// use node_workers::{Runtime, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker.js", 4);
+  /// pool.set_runtime(Runtime::Bun);
+  /// pool.set_binary("/opt/bun/bin/bun");
+  /// ```
+  pub fn set_runtime(&mut self, runtime: Runtime) {
+    self.inner.lock().unwrap().set_runtime(runtime);
+  }
+
+  /// Require a version of node, as a semver requirement such as `>=18.17`, and check it right away so that an
+  /// incompatible installation is caught when the pool is created rather than when a task fails.
+  /// `node --version` only runs once per binary, and the binary is checked again before spawning workers if it's changed
+  /// afterwards with `set_binary`. Ignored for other runtimes.
+  /// ```
+  /// use node_workers::{WorkerError, WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker.js", 4);
+  /// pool.require_node_version(">=14")?;
+  ///
+  /// let err = pool.require_node_version("<14").unwrap_err();
+  /// assert!(matches!(
+  ///   err.downcast_ref::<WorkerError>(),
+  ///   Some(WorkerError::UnsupportedNode { .. })
+  /// ));
+  /// # Ok(())
+  /// # }
+  /// ```
+  /// ## Errors
+  ///
+  /// Fails with `WorkerError::UnsupportedNode` if the version of node doesn't satisfy the requirement.
+  /// Fails with a plain error if the requirement is invalid or the version of node can't be found.
+  /// The requirement is only applied to the pool once it's satisfied.
+  pub fn require_node_version(&mut self, requirement: &str) -> Result<()> {
+    let requirement = VersionReq::parse(requirement)
+      .with_context(|| format!("invalid node version requirement: {}", requirement))?;
+    self.inner.lock().unwrap().require_node_version(requirement)
+  }
+
+  /// Share a limit on the number of running processes with other pools
+  pub(crate) fn set_process_limit(&mut self, limit: Arc<ProcessLimit>) {
+    self.inner.lock().unwrap().limit = Some(limit);
+  }
+
+  /// Watch the worker script, and optionally files matching some glob patterns, and replace the workers when they change.
+  /// New tasks are performed by freshly spawned workers, while workers performing a task exit once it's done.
+  /// Meant for development, so that changes to the JS code don't require restarting the program.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.enable_hot_reload(&["examples/user-files/**/*.ts"])?;
+  /// # Ok(())
+  /// # }
+  /// ```
+  /// ## Errors
+  ///
+  /// Fails if one of the patterns is invalid.
+  pub fn enable_hot_reload(&mut self, patterns: &[&str]) -> Result<()> {
+    hot_reload::watch(Arc::downgrade(&self.inner), patterns)
+  }
+
+  /// Check the idle workers every `interval`, by sending them a heartbeat they must answer within `timeout`.
+  /// A worker whose event loop is stuck, for instance in a synchronous loop left behind by a task, is marked as
+  /// `WorkerHealth::Unresponsive` and its process is replaced before it's given another task.
+  /// The health of each worker is reported in `stats`.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// use std::time::Duration;
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.enable_heartbeat(Duration::from_secs(10), Duration::from_secs(1));
+  /// ```
+  pub fn enable_heartbeat(&mut self, interval: Duration, timeout: Duration) {
+    heartbeat::watch(Arc::downgrade(&self.inner), interval, timeout);
+  }
+
+  /// Set how long a worker has to get ready once spawned, or `None` to wait indefinitely. Defaults to 30 seconds.
+  /// A worker that exits or doesn't print `READY` in time fails its task with `WorkerError::BootFailed`,
+  /// which includes its exit status and its last lines of output.
+  /// ```
+  /// use node_workers::{WorkerError, WorkerPool};
+  /// use std::time::Duration;
+  ///
+  /// let mut pool = WorkerPool::from_source("throw new Error('oops');", 1).unwrap();
+  /// pool.set_boot_timeout(Some(Duration::from_secs(5)));
+  /// let err = pool.run_worker("ping", 1).try_join().unwrap_err();
+  /// if let Some(WorkerError::BootFailed { output, .. }) = err.downcast_ref::<WorkerError>() {
+  ///   assert!(output.iter().any(|line| line.contains("Error: oops")));
+  /// }
+  /// ```
+  pub fn set_boot_timeout(&mut self, timeout: Option<Duration>) {
+    self.inner.lock().unwrap().boot_timeout = timeout;
+  }
+
+  /// Limit the resources of worker processes, such as their memory or CPU time. Only applies to workers created afterwards.
+  /// A worker killed for exceeding a limit fails its task with `WorkerError::ResourceLimitExceeded`.
+  /// ```
+  /// use node_workers::{Resource, ResourceLimits, WorkerError, WorkerPool};
+  ///
+  /// let source = "bridge({ leak: () => { const a = []; while (true) a.push(new Array(1e6).fill(0)); } });";
+  /// let mut pool = WorkerPool::from_source(source, 1).unwrap();
+  /// pool.set_resource_limits(ResourceLimits {
+  ///   max_heap_mb: Some(64),
+  ///   ..Default::default()
+  /// });
+  /// let err = pool.run_worker("leak", 1).try_join().unwrap_err();
+  /// assert!(matches!(
+  ///   err.downcast_ref::<WorkerError>(),
+  ///   Some(WorkerError::ResourceLimitExceeded { resource: Resource::Memory, .. })
+  /// ));
+  /// ```
+  pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+    self.inner.lock().unwrap().resources = Some(Arc::new(limits));
+  }
+
+  /// Configure how tasks failing because of their worker are retried. Tasks are not retried by default.
+  /// When every attempt failed, the error of the last one is returned with the number of attempts as context.
+  /// ```
+  /// use node_workers::{RetryPolicy, WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.set_retry_policy(RetryPolicy {
+  ///   max_attempts: 3,
+  ///   idempotent: vec!["fib2".into()],
+  ///   ..Default::default()
+  /// });
+  /// let thread = pool.run_worker("fib2", 10u32);
+  /// assert_eq!(thread.get_result::<u32>()?, Some(89));
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+    self.inner.lock().unwrap().retry = Arc::new(policy);
+  }
+
+  /// Configure when the pool stops spawning workers that keep failing to boot, for instance because the worker script is broken.
+  /// Once the circuit is open, tasks fail right away with `WorkerError::PoolUnavailable` until the cooldown is over.
+  /// A single worker is then spawned to probe the script: the pool is available again if it boots, and unavailable for another cooldown otherwise.
+  ///
+  /// Disabled by default, `None` disables it again.
+  /// ```
+  /// use node_workers::{CircuitBreakerPolicy, WorkerError, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::from_source("process.exit(1);", 1).unwrap();
+  /// pool.set_circuit_breaker(Some(CircuitBreakerPolicy {
+  ///   failure_threshold: 2,
+  ///   ..Default::default()
+  /// }));
+  /// for _ in 0..2 {
+  ///   assert!(pool.run_worker("ping", 1).join().is_err());
+  /// }
+  /// let err = pool.run_worker("ping", 1).try_join().unwrap_err();
+  /// assert!(matches!(
+  ///   err.downcast_ref::<WorkerError>(),
+  ///   Some(WorkerError::PoolUnavailable { failures: 2, .. })
+  /// ));
+  /// ```
+  pub fn set_circuit_breaker(&mut self, policy: Option<CircuitBreakerPolicy>) {
+    self.breaker.set_policy(policy);
+  }
+
+  /// Enable or disable the preloading of the bridge embedded in the crate. Enabled by default.
+  ///
+  /// Workers are spawned with `-r` (or the equivalent flag of their runtime) to preload the bridge, which defines a global `bridge` function
+  /// so that worker scripts don't need to require the npm package. Disable it when using a binary that doesn't support `-r`.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::from_source("bridge({ double: (n) => n * 2 });", 1)?;
+  /// let result = pool.run_worker("double", 21).get_result::<u32>()?;
+  /// assert_eq!(result, Some(42));
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn set_bridge_preload(&mut self, enabled: bool) {
+    self.inner.lock().unwrap().preload_bridge = enabled;
+  }
+
   /// Enable or disable logging
   pub fn with_debug(&mut self, debug: bool) {
     self.inner.lock().unwrap().with_debug(debug);
   }
 
+  /// Set the maximum number of tasks that can wait for a worker. The queue is unbounded by default.
+  /// What happens when a task is submitted to a full queue is configured with `set_queue_policy`.
+  /// With a capacity of 0, every task is rejected with `WorkerError::QueueFull`.
+  /// ```
+  /// use node_workers::{QueuePolicy, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.set_queue_capacity(100);
+  /// pool.set_queue_policy(QueuePolicy::FailFast);
+  /// ```
+  pub fn set_queue_capacity(&mut self, capacity: usize) {
+    self.queue.set_capacity(capacity);
+  }
+
+  /// Configure what happens when a task is submitted while the queue is full. Defaults to `QueuePolicy::Block`.
+  pub fn set_queue_policy(&mut self, policy: QueuePolicy) {
+    self.queue.set_policy(policy);
+  }
+
+  /// Configure how fast queued tasks gain priority while they wait, to prevent low priority tasks from being starved.
+  /// A task's priority is increased by one for every `aging` period spent in the queue. Defaults to one second, `None` disables aging.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// use std::time::Duration;
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.set_priority_aging(Some(Duration::from_millis(100)));
+  /// ```
+  pub fn set_priority_aging(&mut self, aging: Option<Duration>) {
+    self.queue.set_aging(aging);
+  }
+
+  /// Configure how long a cancelled task is given to stop before its worker is killed and respawned. Defaults to 5 seconds.
+  /// This only applies to handles of tasks submitted after the call.
+  pub fn set_cancel_timeout(&mut self, timeout: Duration) {
+    self.cancel_timeout = timeout;
+  }
+
   /// Run a single worker in a thread. This method returns the created thread, not the result of the worker.
   /// Use this if you need more control on the pool.
   /// ```
@@ -78,20 +385,90 @@ impl WorkerPool {
   /// # Ok(())
   /// # }
   /// ```
+  ///
+  /// The task is pushed to the queue of the pool. If the queue is full, this method blocks when using `QueuePolicy::Block`,
+  /// otherwise the returned thread resolves with a `WorkerError`.
   pub fn run_worker<P: AsPayload>(&mut self, cmd: &str, payload: P) -> WorkerThread {
-    let payload = payload.to_payload();
-    let cmd = cmd.to_string();
-    let inner = self.inner.clone();
+    self.run_worker_with_options(cmd, payload, TaskOptions::default())
+  }
 
-    // spawn a thread so that inner.get_available_worker() doesn't block
-    let handle = std::thread::spawn(move || {
-      let inner = inner.clone();
-      let mut pool = inner.lock().unwrap();
-      let res = pool.run_worker(cmd, payload);
-      drop(pool);
-      res.join().unwrap()
-    });
-    WorkerThread::from_handle(handle)
+  /// Same as `run_worker`, with options such as the priority of the task.
+  /// Queued tasks with a higher priority are dispatched first.
+  /// ```
+  /// use node_workers::{TaskOptions, WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// let bulk = pool.run_worker("fib2", 30u32);
+  /// let urgent = pool.run_worker_with_options(
+  ///   "fib2",
+  ///   10u32,
+  ///   TaskOptions {
+  ///     priority: 10,
+  ///     ..Default::default()
+  ///   },
+  /// );
+  /// println!("urgent result: {:?}", urgent.get_result::<u32>()?);
+  /// # bulk.try_join()?;
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn run_worker_with_options<P: AsPayload>(
+    &mut self,
+    cmd: &str,
+    payload: P,
+    options: TaskOptions,
+  ) -> WorkerThread {
+    let (task, handle) = self.new_task(cmd, payload.to_payload(), options);
+    // a rejected task resolves its handle with the error
+    let _ = self.enqueue(task, true);
+    handle
+  }
+
+  /// Same as `run_worker`, but never blocks. If the queue is full, `WorkerError::QueueFull` is returned right away,
+  /// unless the pool uses `QueuePolicy::DropOldest`.
+  /// ```
+  /// use node_workers::{WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// pool.set_queue_capacity(10);
+  /// let thread = pool.try_run_worker("fib2", 20u32)?;
+  /// println!("result: {:?}", thread.get_result::<u32>()?);
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn try_run_worker<P: AsPayload>(&mut self, cmd: &str, payload: P) -> Result<WorkerThread> {
+    let (task, handle) = self.new_task(cmd, payload.to_payload(), TaskOptions::default());
+    self.enqueue(task, false)?;
+    Ok(handle)
+  }
+
+  /// Create a task and the handle resolving with its result
+  fn new_task(&self, cmd: &str, payload: Value, options: TaskOptions) -> (Task, WorkerThread) {
+    new_task(&self.queue, self.cancel_timeout, cmd, payload, options)
+  }
+
+  /// Start the dispatcher thread if needed
+  fn start_dispatcher(&mut self) {
+    if self.dispatcher.is_none() {
+      let inner = self.inner.clone();
+      let queue = self.queue.clone();
+      self.dispatcher = Some(std::thread::spawn(move || dispatch(inner, queue)));
+    }
+  }
+
+  /// Push a task to the queue, starting the dispatcher thread if needed.
+  /// The task is rejected right away if the workers are known not to define it, or if the pool is unavailable.
+  fn enqueue(&mut self, task: Task, blocking: bool) -> Result<(), WorkerError> {
+    if let Err(error) = check_submission(&self.breaker, &self.info, &task.cmd) {
+      task.reject(error.clone());
+      return Err(error);
+    }
+    self.start_dispatcher();
+    self.queue.push(task, blocking)
   }
 
   /// Dispatch a task between available workers with a set of payloads.
@@ -112,7 +489,8 @@ impl WorkerPool {
   /// ```
   /// ## Errors
   ///
-  /// Each worker is run in a thread, and `perform()` will return an error variant if one of them panick.
+  /// Fails with the error of the first payload whose task failed, which can be a `WorkerError` such as `WorkerError::Deserialize`.
+  /// Payloads are pushed to the queue of the pool, so this also fails if the queue is full and the pool doesn't use `QueuePolicy::Block`.
   pub fn perform<T: DeserializeOwned, P: AsPayload>(
     &mut self,
     cmd: &str,
@@ -123,11 +501,8 @@ impl WorkerPool {
     let mut handles = Vec::new();
     for (n, payload) in payloads.into_iter().map(|x| x.to_payload()).enumerate() {
       print_debug!(debug, "[pool] (task {}) start of iteration", n);
-      let handle = self
-        .inner
-        .lock()
-        .unwrap()
-        .run_worker(cmd.to_string(), payload);
+      let (task, handle) = self.new_task(cmd, payload, TaskOptions::default());
+      self.enqueue(task, true)?;
       handles.push(handle);
       print_debug!(debug, "[pool] (task {}) end of iteration", n);
     }
@@ -137,16 +512,179 @@ impl WorkerPool {
       .enumerate()
       .map(|(n, x)| {
         print_debug!(debug, "[pool] (thread {}) joined", n);
-        let res = x.get_result::<T>();
-        if let Ok(res) = res {
-          Ok(res)
-        } else {
-          bail!("failed to join thread")
-        }
+        x.get_result::<T>()
       })
       .collect::<Result<Vec<_>, _>>()
   }
 
+  /// Same as `perform`, but returns the result of each payload instead of failing as a whole, in the order of the payloads.
+  /// With `SettleMode::FailFast`, the remaining tasks are cancelled as soon as one of them fails, and resolve with `WorkerError::Cancelled`
+  /// unless they completed in the meantime.
+  /// ```
+  /// use node_workers::{SettleMode, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// let results = pool.perform_settled::<u64, _>("fib2", vec![10, 20, 30], SettleMode::All);
+  /// for (n, result) in results.into_iter().enumerate() {
+  ///   match result {
+  ///     Ok(res) => println!("payload {}: {:?}", n, res),
+  ///     Err(err) => println!("payload {} failed: {}", n, err),
+  ///   }
+  /// }
+  /// ```
+  pub fn perform_settled<T: DeserializeOwned, P: AsPayload>(
+    &mut self,
+    cmd: &str,
+    payloads: Vec<P>,
+    mode: SettleMode,
+  ) -> Vec<Result<Option<T>>> {
+    let mut handles = Vec::new();
+    for payload in payloads.into_iter().map(|x| x.to_payload()) {
+      let (task, handle) = self.new_task(cmd, payload, TaskOptions::default());
+      // a rejected task resolves its handle with the error
+      let _ = self.enqueue(task, true);
+      handles.push(handle);
+    }
+
+    if mode == SettleMode::All {
+      return handles.into_iter().map(|x| x.get_result::<T>()).collect();
+    }
+    let mut results = handles.iter().map(|_| None).collect::<Vec<_>>();
+    let mut indices = (0..handles.len()).collect::<Vec<_>>();
+    let mut failed = false;
+    while let Some(n) = WorkerThread::wait_any(&mut handles) {
+      let index = indices.swap_remove(n);
+      let result = handles.swap_remove(n).get_result::<T>();
+      if result.is_err() && !failed {
+        failed = true;
+        let debug = self.inner.lock().unwrap().debug;
+        print_debug!(
+          debug,
+          "[pool] (task {}) failed, cancelling the others",
+          index
+        );
+        for handle in &handles {
+          handle.cancel();
+        }
+      }
+      results[index] = Some(result);
+    }
+    results.into_iter().flatten().collect()
+  }
+
+  /// Same as `perform`, but returns an iterator yielding the result of each payload as soon as its task completes,
+  /// along with the index of the payload. Results are yielded in completion order, not in the order of the payloads.
+  ///
+  /// Payloads are submitted progressively as the queue of the pool has room, so results can be consumed early
+  /// even with a bounded queue. Dropping the iterator stops submitting the remaining payloads.
+  /// Payloads submitted once the pool is dropped resolve with `WorkerError::PoolClosed`.
+  /// ```
+  /// use node_workers::{WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// let payloads = vec![30, 10, 20];
+  /// let total = payloads.len();
+  /// for (done, (n, result)) in pool.perform_iter::<u64, _>("fib2", payloads).enumerate() {
+  ///   println!("[{}/{}] payload {}: {:?}", done + 1, total, n, result?);
+  /// }
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn perform_iter<T: DeserializeOwned, P: AsPayload>(
+    &mut self,
+    cmd: &str,
+    payloads: Vec<P>,
+  ) -> PerformIter<T> {
+    self.start_dispatcher();
+    PerformIter::new(
+      self.queue.clone(),
+      self.breaker.clone(),
+      self.info.clone(),
+      self.cancel_timeout,
+      cmd,
+      payloads.into_iter().map(|x| x.to_payload()).collect(),
+    )
+  }
+
+  /// What the bridge of the workers reported when they booted, such as its protocol version and the available tasks.
+  /// Returns `None` until a worker is ready.
+  /// ```
+  /// use node_workers::{WorkerPool, PROTOCOL_VERSION};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// pool.warmup(1).join().unwrap();
+  /// let info = pool.bridge_info().unwrap();
+  /// assert_eq!(info.version, PROTOCOL_VERSION);
+  /// assert!(info.tasks.contains(&"fib2".to_string()));
+  /// ```
+  pub fn bridge_info(&self) -> Option<BridgeInfo> {
+    self.info.lock().unwrap().clone()
+  }
+
+  /// Names of the tasks defined by the worker script, once a worker is ready.
+  /// Submitting a task that isn't in this list fails with `WorkerError::TaskNotFound`.
+  pub fn tasks(&self) -> Option<Vec<String>> {
+    self.bridge_info().map(|info| info.tasks)
+  }
+
+  /// Get a snapshot of the state of the pool and of each of its workers.
+  /// This doesn't wait for busy workers, and can be called while tasks are running.
+  /// ```
+  /// use node_workers::{WorkerPool};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.perform::<u64, _>("fib2", vec![10, 20])?;
+  ///
+  /// let stats = pool.stats();
+  /// assert_eq!(stats.tasks_completed, 2);
+  /// for worker in stats.workers {
+  ///   println!("worker {} (pid {:?}) performed {} tasks", worker.id, worker.pid, worker.tasks_done);
+  /// }
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn stats(&self) -> PoolStats {
+    self.metrics.snapshot(self.max_workers, self.queue.len())
+  }
+
+  /// Set the number of workers that must be ready to take on tasks during a rolling restart.
+  /// If fewer workers are running when the restart begins, new ones are booted first. Defaults to 0.
+  pub fn set_min_ready_workers(&mut self, n: usize) {
+    self.min_ready_workers = n.min(self.max_workers);
+  }
+
+  /// Replace the process of every worker, one at a time, in the background.
+  /// The replacement of a worker is booted while its current process keeps serving tasks,
+  /// and processes are swapped once the worker is done with its task, so the pool keeps running at full capacity.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// pool.set_min_ready_workers(1);
+  /// let restart = pool.restart_all();
+  /// let result = pool.run_worker("fib2", 20u32).get_result::<u32>()?;
+  /// let progress = restart.join()?;
+  /// println!("{}/{} workers replaced", progress.replaced, progress.total);
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn restart_all(&mut self) -> RestartHandle {
+    rolling_restart::restart(self.inner.clone(), self.min_ready_workers)
+  }
+
+  /// Change the worker script, and replace the running workers with a rolling restart (see `restart_all`).
+  /// Workers spawned from now on run the new script, with the node binary discovered for it unless it was given with `set_binary`.
+  pub fn set_worker_path(&mut self, worker_path: &str) -> RestartHandle {
+    self.inner.lock().unwrap().set_worker_path(worker_path);
+    self.restart_all()
+  }
+
   /// Boot a maximum of *n* workers, making them ready to take on a task right away.
   /// ```rust
   /// use node_workers::{WorkerPool};
@@ -161,13 +699,105 @@ impl WorkerPool {
   /// ```
   pub fn warmup(&self, nbr_workers: usize) -> JoinHandle<()> {
     let inner = self.inner.clone();
-    std::thread::spawn(move || inner.lock().unwrap().warmup(nbr_workers).unwrap())
+    std::thread::spawn(move || {
+      let handles = inner.lock().unwrap().warmup(nbr_workers);
+      let res = handles.and_then(|handles| {
+        for handle in handles {
+          match handle.join() {
+            Ok(res) => res?,
+            Err(_) => bail!("thread panicked"),
+          }
+        }
+        Ok(())
+      });
+      if let Err(err) = res {
+        panic!("{:#}", err);
+      }
+    })
+  }
+}
+
+impl Drop for WorkerPool {
+  fn drop(&mut self) {
+    // let the dispatcher exit once the remaining tasks are dispatched
+    self.queue.close();
+  }
+}
+
+/// Check that a task can be submitted: the pool must not be unavailable, and the workers must define the task
+pub(crate) fn check_submission(
+  breaker: &CircuitBreaker,
+  info: &SharedBridgeInfo,
+  cmd: &str,
+) -> Result<(), WorkerError> {
+  breaker.check()?;
+  check_task(info, cmd)
+}
+
+/// Check that the workers define a task, if they reported their tasks already
+fn check_task(info: &SharedBridgeInfo, cmd: &str) -> Result<(), WorkerError> {
+  match &*info.lock().unwrap() {
+    Some(info) if !info.tasks.iter().any(|task| task == cmd) => Err(WorkerError::TaskNotFound {
+      command: cmd.to_string(),
+      available: info.tasks.clone(),
+    }),
+    _ => Ok(()),
+  }
+}
+
+/// Create a task and the handle resolving with its result
+pub(crate) fn new_task(
+  queue: &Arc<TaskQueue>,
+  cancel_timeout: Duration,
+  cmd: &str,
+  payload: Value,
+  options: TaskOptions,
+) -> (Task, WorkerThread) {
+  let (sender, receiver) = channel();
+  let task = Task::with_options(cmd.to_string(), payload, options, sender);
+  let canceller = CancelHandle::new(task.control.clone(), Arc::downgrade(queue), cancel_timeout);
+  (task, WorkerThread::from_receiver(receiver, canceller))
+}
+
+/// Assign queued tasks to workers as soon as one is available
+fn dispatch(inner: Arc<Mutex<WorkerPoolInner>>, queue: Arc<TaskQueue>) {
+  while queue.wait_for_task() {
+    let worker = wait_for_worker(&inner);
+    let mut pool = inner.lock().unwrap();
+    match queue.pop() {
+      Some(task) => pool.run_task(worker, task),
+      None => {
+        worker.lock().unwrap().idle = true;
+        pool.availability.notify();
+      }
+    }
   }
 }
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison, clippy::redundant_pattern_matching)]
 mod tests {
-  use crate::worker_pool::WorkerPool;
+  use crate::{
+    as_payload::EmptyPayload,
+    bridge::PROTOCOL_VERSION,
+    circuit_breaker::CircuitBreakerPolicy,
+    error::WorkerError,
+    node_binary,
+    resource_limits::{Resource, ResourceLimits},
+    retry::RetryPolicy,
+    runtime::Runtime,
+    stats::WorkerHealth,
+    task_queue::QueuePolicy,
+    worker_pool::{SettleMode, WorkerPool},
+    worker_thread::WorkerThread,
+  };
+  use std::time::Duration;
+
+  fn wait_for_empty_queue(pool: &WorkerPool) {
+    while pool.stats().queued_tasks > 0 {
+      std::thread::sleep(Duration::from_millis(5));
+    }
+  }
 
   #[test]
   pub fn create_worker_when_needed() {
@@ -240,6 +870,1081 @@ mod tests {
     }
   }
 
+  #[test]
+  pub fn stats() {
+    let mut pool = WorkerPool::setup("examples/worker", 2);
+    pool.perform::<u64, _>("fib2", vec![10, 20, 30]).unwrap();
+    let _ = pool.perform::<(), _>("error", vec![1]);
+
+    let stats = pool.stats();
+    assert_eq!(stats.tasks_completed, 3);
+    assert_eq!(stats.tasks_failed, 1);
+    assert_eq!(stats.busy_workers, 0);
+    assert_eq!(stats.queue_wait.count, 4);
+    assert_eq!(stats.task_latency["fib2"].count, 3);
+    assert_eq!(stats.task_latency["error"].count, 1);
+    assert_eq!(stats.workers.iter().map(|w| w.tasks_done).sum::<u64>(), 3);
+    // the worker that performed the failing task was killed
+    assert_eq!(stats.workers.iter().filter(|w| w.ready).count(), 1);
+    for worker in stats.workers {
+      assert_eq!(worker.pid.is_some(), worker.ready);
+      assert_eq!(worker.uptime.is_some(), worker.ready);
+      assert!(worker.last_task_duration.is_some());
+    }
+  }
+
+  #[test]
+  pub fn stats_worker_exited() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    pool.run_worker("fib2", 10).join().unwrap();
+    let worker = &pool.stats().workers[0];
+    assert!(worker.ready);
+    assert!(worker.pid.is_some());
+
+    // a failing task kills the worker
+    let _ = pool.run_worker("error", 1).join();
+    let worker = &pool.stats().workers[0];
+    assert!(!worker.ready);
+    assert_eq!(worker.pid, None);
+    assert_eq!(worker.uptime, None);
+
+    pool.run_worker("fib2", 10).join().unwrap();
+    pool.inner.lock().unwrap().reload();
+    let worker = &pool.stats().workers[0];
+    assert!(!worker.ready);
+    assert_eq!(worker.pid, None);
+  }
+
+  #[test]
+  pub fn pool_usable_while_workers_are_busy() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    let first = pool.run_worker("sleep", 500);
+    let second = pool.run_worker("fib2", 10);
+    std::thread::sleep(Duration::from_millis(100));
+    // the dispatcher is waiting for the worker without holding the pool
+    let start = std::time::Instant::now();
+    pool.with_debug(false);
+    assert!(start.elapsed() < Duration::from_millis(300));
+    assert_eq!(pool.stats().queued_tasks, 1);
+
+    assert_eq!(first.get_result::<u32>().unwrap(), Some(500));
+    assert_eq!(second.get_result::<u32>().unwrap(), Some(89));
+  }
+
+  #[test]
+  pub fn queue_fail_fast() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    pool.set_queue_capacity(1);
+    pool.set_queue_policy(QueuePolicy::FailFast);
+
+    let first = pool.try_run_worker("fib2", 30).unwrap();
+    wait_for_empty_queue(&pool);
+    let second = pool.try_run_worker("fib2", 10).unwrap();
+    let err = pool.try_run_worker("fib2", 10).unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::QueueFull { capacity: 1 })
+    );
+    assert!(pool.run_worker("fib2", 10).join().is_err());
+
+    assert_eq!(first.get_result::<u32>().unwrap(), Some(1346269));
+    assert_eq!(second.get_result::<u32>().unwrap(), Some(89));
+  }
+
+  #[test]
+  pub fn queue_drop_oldest() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    pool.set_queue_capacity(1);
+    pool.set_queue_policy(QueuePolicy::DropOldest);
+
+    let first = pool.try_run_worker("fib2", 30).unwrap();
+    wait_for_empty_queue(&pool);
+    let second = pool.try_run_worker("fib2", 10).unwrap();
+    let third = pool.try_run_worker("fib2", 11).unwrap();
+
+    let err = second.try_join().unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::Dropped)
+    );
+    assert_eq!(first.get_result::<u32>().unwrap(), Some(1346269));
+    assert_eq!(third.get_result::<u32>().unwrap(), Some(144));
+  }
+
+  #[test]
+  pub fn queue_block() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    pool.set_queue_capacity(1);
+    let result = pool
+      .perform::<u32, _>("fib2", vec![10, 11, 12, 13])
+      .unwrap();
+    assert_eq!(result, vec![Some(89), Some(144), Some(233), Some(377)]);
+  }
+
+  #[test]
+  pub fn reuse_worker_after_failure() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    assert!(pool.run_worker("error", 1).join().is_err());
+    let result = pool.run_worker("fib2", 10).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(89));
+  }
+
+  fn wait_for_busy_worker(pool: &WorkerPool) {
+    while pool.stats().busy_workers == 0 {
+      std::thread::sleep(Duration::from_millis(5));
+    }
+    // let the worker receive the task
+    std::thread::sleep(Duration::from_millis(200));
+  }
+
+  #[test]
+  pub fn cancel_queued_task() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    let first = pool.run_worker("sleep", 500);
+    wait_for_empty_queue(&pool);
+    let second = pool.run_worker("fib2", 10);
+    assert!(second.cancel());
+    assert!(!second.cancel());
+    assert_eq!(pool.stats().queued_tasks, 0);
+
+    let err = second.try_join().unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::Cancelled)
+    );
+    assert_eq!(first.get_result::<u32>().unwrap(), Some(500));
+  }
+
+  #[test]
+  pub fn cancel_running_task() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    let thread = pool.run_worker("sleep", 10_000);
+    wait_for_busy_worker(&pool);
+    let pid = pool.stats().workers[0].pid;
+    assert!(thread.cancel());
+
+    let err = thread.try_join().unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::Cancelled)
+    );
+    // the task stopped on its own, so the worker wasn't killed
+    let result = pool.run_worker("fib2", 10).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(89));
+    assert_eq!(pool.stats().workers[0].pid, pid);
+  }
+
+  #[test]
+  pub fn kill_worker_ignoring_cancellation() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    pool.set_cancel_timeout(Duration::from_millis(100));
+    let thread = pool.run_worker("fib2", 50);
+    wait_for_busy_worker(&pool);
+    let pid = pool.stats().workers[0].pid;
+    let cancel = thread.cancel_handle().unwrap();
+    assert!(cancel.cancel());
+
+    let err = thread.try_join().unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::Cancelled)
+    );
+    let result = pool.run_worker("fib2", 10).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(89));
+    assert_ne!(pool.stats().workers[0].pid, pid);
+  }
+
+  #[test]
+  pub fn poll_results() {
+    let mut pool = WorkerPool::setup("examples/worker", 2);
+    let mut slow = pool.run_worker("sleep", 500);
+    let fast = pool.run_worker("fib2", 10);
+    let mut handles = vec![fast];
+
+    assert_eq!(WorkerThread::wait_any(&mut handles), Some(0));
+    assert!(handles[0].is_finished());
+    assert_eq!(
+      handles[0].try_get_result::<u32>().unwrap().unwrap(),
+      Some(89)
+    );
+    assert!(handles[0].try_get_result::<u32>().unwrap().is_err());
+
+    assert!(!slow.is_finished());
+    assert!(slow.try_get_result::<u32>().is_none());
+    assert!(slow
+      .get_result_timeout::<u32>(Duration::from_millis(10))
+      .is_none());
+    let result = slow.get_result_timeout::<u32>(Duration::from_secs(10));
+    assert_eq!(result.unwrap().unwrap(), Some(500));
+    assert_eq!(WorkerThread::wait_any(&mut []), None);
+  }
+
+  #[test]
+  pub fn perform_settled() {
+    let mut pool = WorkerPool::setup("examples/worker", 3);
+    let results = pool.perform_settled::<i32, _>("sleep", vec![10, -1, 20], SettleMode::All);
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].as_ref().unwrap(), &Some(10));
+    assert!(results[1].is_err());
+    assert_eq!(results[2].as_ref().unwrap(), &Some(20));
+
+    // the slow tasks are cancelled once one fails
+    let start = std::time::Instant::now();
+    let results =
+      pool.perform_settled::<i32, _>("sleep", vec![5000, -1, 5000], SettleMode::FailFast);
+    assert!(start.elapsed() < Duration::from_secs(4));
+    assert_eq!(results.len(), 3);
+    for n in [0, 2] {
+      let err = results[n].as_ref().unwrap_err();
+      assert_eq!(
+        err.downcast_ref::<WorkerError>(),
+        Some(&WorkerError::Cancelled)
+      );
+    }
+    let err = results[1].as_ref().unwrap_err();
+    assert_ne!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::Cancelled)
+    );
+  }
+
+  #[test]
+  pub fn deserialize_error() {
+    #[derive(serde::Deserialize, Debug)]
+    #[allow(dead_code)]
+    struct User {
+      name: String,
+      phones: Vec<u32>,
+    }
+
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    let err = pool
+      .run_worker("getUser", EmptyPayload::new())
+      .get_result::<User>()
+      .unwrap_err();
+    match err.downcast_ref::<WorkerError>() {
+      Some(WorkerError::Deserialize {
+        command,
+        worker,
+        path,
+        raw,
+        ..
+      }) => {
+        assert_eq!(command.as_deref(), Some("getUser"));
+        assert_eq!(*worker, Some(1));
+        assert_eq!(path, "phones[0]");
+        assert!(raw.contains("\"name\":\"Foo\""));
+      }
+      _ => panic!("unexpected error: {:?}", err),
+    }
+    // the worker is still usable
+    let result = pool.run_worker("fib2", 10).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(89));
+  }
+
+  #[test]
+  pub fn logged_markers() {
+    let source = r#"bridge({ double: (n) => { console.log("OK computing", n); return n * 2; } });"#;
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    let results = pool.perform::<u32, _>("double", vec![21, 1]).unwrap();
+    assert_eq!(results, vec![Some(42), Some(2)]);
+  }
+
+  #[test]
+  pub fn reload_locked_worker() {
+    let mut pool = WorkerPool::from_source("bridge({ pid: () => process.pid });", 1).unwrap();
+    let pid = pool.run_worker("pid", 0).get_result::<u32>().unwrap();
+    {
+      // like a worker being pinged by the heartbeat
+      let worker = pool.inner.lock().unwrap().workers[0].clone();
+      let _worker = worker.lock().unwrap();
+      pool.inner.lock().unwrap().reload();
+    }
+    let new_pid = pool.run_worker("pid", 0).get_result::<u32>().unwrap();
+    assert_ne!(new_pid, pid);
+  }
+
+  #[test]
+  pub fn hot_reload() {
+    let dir = std::env::temp_dir().join(format!("node-workers-hot-reload-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("worker.js");
+    let bridge = std::fs::canonicalize("dist/bridge.js").unwrap();
+    let write_script = |version: u32| {
+      let source = format!(
+        "const {{ bridge }} = require({:?});\nbridge({{ version: () => {}, task{}: () => true }});\n",
+        bridge, version, version
+      );
+      std::fs::write(&script, source).unwrap();
+    };
+    write_script(1);
+
+    let mut pool = WorkerPool::setup(script.to_str().unwrap(), 1);
+    pool.enable_hot_reload(&[]).unwrap();
+    let version = |pool: &mut WorkerPool| {
+      pool
+        .run_worker("version", EmptyPayload::new())
+        .get_result::<u32>()
+        .unwrap()
+    };
+    assert_eq!(version(&mut pool), Some(1));
+
+    write_script(2);
+    // tasks added by the new script are accepted
+    let start = std::time::Instant::now();
+    while pool
+      .run_worker("task2", EmptyPayload::new())
+      .join()
+      .is_err()
+    {
+      assert!(start.elapsed() < Duration::from_secs(10));
+      std::thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(version(&mut pool), Some(2));
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  pub fn rolling_restart() {
+    let mut pool = WorkerPool::setup("examples/worker", 2);
+    pool.set_min_ready_workers(2);
+    // boots the missing workers
+    let progress = pool.restart_all().join().unwrap();
+    assert_eq!(progress.total, 2);
+    assert_eq!(progress.replaced, 2);
+    let pids = |pool: &WorkerPool| {
+      let mut pids = pool
+        .stats()
+        .workers
+        .iter()
+        .map(|w| w.pid.unwrap())
+        .collect::<Vec<_>>();
+      pids.sort();
+      pids
+    };
+    let before = pids(&pool);
+    assert_eq!(before.len(), 2);
+
+    // workers keep serving tasks during the restart
+    let slow = pool.run_worker("sleep", 300);
+    let restart = pool.restart_all();
+    let results = pool.perform::<u32, _>("fib2", vec![10, 11]).unwrap();
+    assert_eq!(results, vec![Some(89), Some(144)]);
+    assert_eq!(slow.get_result::<u32>().unwrap(), Some(300));
+    let progress = restart.join().unwrap();
+    assert!(progress.finished);
+    assert!(progress.errors.is_empty());
+    let after = pids(&pool);
+    assert!(after.iter().all(|pid| !before.contains(pid)));
+
+    // failures are reported and the current processes are kept
+    let restart = pool.set_worker_path("examples/missing-worker");
+    let err = restart.join().unwrap_err();
+    assert!(err.to_string().contains("failed to restart worker"));
+    assert_eq!(pids(&pool), after);
+    pool.set_worker_path("examples/worker").join().unwrap();
+    let result = pool.run_worker("fib2", 10).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(89));
+  }
+
+  #[test]
+  pub fn from_source() {
+    let bridge = std::fs::canonicalize("dist/bridge.js").unwrap();
+    let source = format!(
+      "const {{ bridge }} = require({:?});\nbridge({{ double: (n) => n * 2 }});\n",
+      bridge
+    );
+    let mut pool = WorkerPool::from_source(&source, 1).unwrap();
+    let path = pool.inner.lock().unwrap().worker_path.to_string();
+    assert!(std::path::Path::new(&path).exists());
+    let result = pool.run_worker("double", 21).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(42));
+    // the file is removed once the dispatcher of the pool exits
+    drop(pool);
+    let start = std::time::Instant::now();
+    while std::path::Path::new(&path).exists() {
+      assert!(start.elapsed() < Duration::from_secs(5));
+      std::thread::sleep(Duration::from_millis(10));
+    }
+  }
+
+  #[test]
+  pub fn rolling_restart_busy_worker() {
+    let source = r#"
+      const end = Date.now() + 500;
+      while (Date.now() < end) {}
+      bridge({ sleep: (ms) => new Promise((resolve) => setTimeout(() => resolve(ms), ms)) });
+    "#;
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    pool.warmup(1).join().unwrap();
+    let start = std::time::Instant::now();
+    let slow = pool.run_worker("sleep", 1000);
+    wait_for_busy_worker(&pool);
+    // the replacement boots while the task is running
+    let restart = pool.restart_all();
+    assert_eq!(slow.get_result::<u32>().unwrap(), Some(1000));
+    assert_eq!(restart.join().unwrap().replaced, 1);
+    assert!(start.elapsed() < Duration::from_millis(1400));
+  }
+
+  #[test]
+  pub fn global_bridge() {
+    let mut pool = WorkerPool::from_source("bridge({ double: (n) => n * 2 });", 1).unwrap();
+    let result = pool.run_worker("double", 21).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(42));
+
+    let mut pool = WorkerPool::from_source("bridge({ double: (n) => n * 2 });", 1).unwrap();
+    pool.set_bridge_preload(false);
+    assert!(pool.run_worker("double", 21).join().is_err());
+  }
+
+  #[test]
+  pub fn bridge_handshake() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    assert_eq!(pool.tasks(), None);
+    pool.warmup(1).join().unwrap();
+    let info = pool.bridge_info().unwrap();
+    assert_eq!(info.version, PROTOCOL_VERSION);
+    assert!(info.features.contains(&"cancel".to_string()));
+    assert!(pool.tasks().unwrap().contains(&"fib2".to_string()));
+
+    // unknown commands are rejected before being dispatched
+    let err = pool.run_worker("no", 40).try_join().unwrap_err();
+    match err.downcast_ref::<WorkerError>() {
+      Some(WorkerError::TaskNotFound { command, available }) => {
+        assert_eq!(command, "no");
+        assert_eq!(available, &info.tasks);
+      }
+      _ => panic!("unexpected error: {:?}", err),
+    }
+    assert!(pool
+      .perform_iter::<u32, _>("no", vec![1, 2])
+      .all(|(_, res)| res.is_err()));
+    assert_eq!(pool.stats().tasks_failed, 0);
+  }
+
+  #[test]
+  pub fn incompatible_bridge() {
+    let handshakes = [
+      ("READY", None),
+      (
+        r#"READY {"version":99,"features":[],"tasks":["ping"]}"#,
+        Some(99),
+      ),
+    ];
+    for (handshake, found) in handshakes {
+      let source = format!("console.log({:?}); process.stdin.resume();", handshake);
+      let mut pool = WorkerPool::from_source(&source, 1).unwrap();
+      pool.set_bridge_preload(false);
+      let err = pool.run_worker("ping", 1).try_join().unwrap_err();
+      assert_eq!(
+        err.downcast_ref::<WorkerError>(),
+        Some(&WorkerError::IncompatibleBridge {
+          expected: PROTOCOL_VERSION,
+          found
+        })
+      );
+    }
+  }
+
+  #[test]
+  pub fn boot_failure() {
+    let source = "console.log('starting'); throw new Error('oops');";
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    let err = pool.run_worker("ping", 1).try_join().unwrap_err();
+    match err.downcast_ref::<WorkerError>() {
+      Some(WorkerError::BootFailed { status, output }) => {
+        assert!(!status.unwrap().success());
+        assert!(output.contains(&"(stdout) starting".to_string()));
+        assert!(output
+          .iter()
+          .any(|line| line.starts_with("(stderr)") && line.contains("Error: oops")));
+      }
+      _ => panic!("unexpected error: {:?}", err),
+    }
+  }
+
+  #[test]
+  pub fn boot_timeout() {
+    let source = "setInterval(() => {}, 1000);";
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    pool.set_boot_timeout(Some(Duration::from_millis(300)));
+    let start = std::time::Instant::now();
+    let err = pool.run_worker("ping", 1).try_join().unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(5));
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::BootFailed { status: None, .. })
+    ));
+
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    pool.set_boot_timeout(Some(Duration::from_millis(300)));
+    assert!(pool.warmup(1).join().is_err());
+  }
+
+  #[test]
+  pub fn heartbeat() {
+    let source = r#"
+      bridge({
+        pid: () => process.pid,
+        wedge: () => {
+          setTimeout(() => { while (true) {} }, 50);
+          return 1;
+        },
+      });
+    "#;
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    pool.enable_heartbeat(Duration::from_millis(200), Duration::from_millis(300));
+    let pid = pool.run_worker("pid", 0).get_result::<u32>().unwrap();
+    std::thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.stats().workers[0].health, WorkerHealth::Healthy);
+
+    pool.run_worker("wedge", 0).join().unwrap();
+    std::thread::sleep(Duration::from_millis(1500));
+    let stats = pool.stats();
+    assert_ne!(stats.workers[0].pid, pid);
+    let start = std::time::Instant::now();
+    let new_pid = pool.run_worker("pid", 0).get_result::<u32>().unwrap();
+    assert_ne!(new_pid, pid);
+    assert_eq!(stats.workers[0].pid, new_pid);
+    assert!(start.elapsed() < Duration::from_secs(1));
+  }
+
+  #[test]
+  pub fn heartbeat_replaces_while_waiting() {
+    let source = r#"
+      bridge({
+        pid: () => process.pid,
+        wedge: () => {
+          setTimeout(() => { while (true) {} }, 50);
+          return 1;
+        },
+      });
+    "#;
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    pool.enable_heartbeat(Duration::from_millis(100), Duration::from_millis(500));
+    pool.run_worker("wedge", 0).join().unwrap();
+    // the worker is being checked, so the pool waits for it while locked
+    std::thread::sleep(Duration::from_millis(250));
+    let inner = pool.inner.clone();
+    let (sender, receiver) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+      let handle = inner.lock().unwrap().run_worker("pid".into(), 0);
+      sender.send(handle.get_result::<u32>()).unwrap();
+    });
+    let pid = receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(pid.unwrap().is_some());
+  }
+
+  #[test]
+  pub fn retry_policy() {
+    let marker = std::env::temp_dir().join(format!("node-workers-retry-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let source = format!(
+      r#"
+        const fs = require('fs');
+        bridge({{
+          flaky: () => {{
+            if (!fs.existsSync({marker:?})) {{
+              fs.writeFileSync({marker:?}, '');
+              process.exit(1);
+            }}
+            return 42;
+          }},
+          crash: () => process.exit(1),
+        }});
+      "#,
+      marker = marker.to_string_lossy()
+    );
+    let mut pool = WorkerPool::from_source(&source, 1).unwrap();
+    pool.set_retry_policy(RetryPolicy {
+      max_attempts: 3,
+      backoff: Duration::from_millis(100),
+      idempotent: vec!["flaky".into(), "crash".into()],
+      ..Default::default()
+    });
+
+    let mut thread = pool.run_worker("flaky", 0);
+    let result = thread.get_result_timeout::<u32>(Duration::from_secs(10));
+    assert_eq!(result.unwrap().unwrap(), Some(42));
+    assert_eq!(thread.attempts(), 2);
+
+    let start = std::time::Instant::now();
+    let mut thread = pool.run_worker("crash", 0);
+    let result = thread.get_result_timeout::<u32>(Duration::from_secs(10));
+    let err = result.unwrap().unwrap_err();
+    assert!(format!("{:#}", err).contains("task failed after 3 attempts"));
+    assert_eq!(thread.attempts(), 3);
+    // backoff of 100ms, then 200ms
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    // commands that aren't idempotent are not retried once they reached the worker
+    std::fs::remove_file(&marker).unwrap();
+    pool.set_retry_policy(RetryPolicy {
+      max_attempts: 3,
+      ..Default::default()
+    });
+    let thread = pool.run_worker("flaky", 0);
+    let err = thread.try_join().unwrap_err();
+    assert!(!format!("{:#}", err).contains("attempts"));
+
+    // tasks cancelled during the backoff are not attempted again
+    pool.set_retry_policy(RetryPolicy {
+      max_attempts: 3,
+      backoff: Duration::from_secs(1),
+      idempotent: vec!["crash".into()],
+      ..Default::default()
+    });
+    let thread = pool.run_worker("crash", 0);
+    std::thread::sleep(Duration::from_millis(500));
+    thread.cancel();
+    assert_eq!(thread.attempts(), 1);
+    let err = thread.try_join().unwrap_err();
+    assert_eq!(
+      err.downcast_ref::<WorkerError>(),
+      Some(&WorkerError::Cancelled)
+    );
+    assert_eq!(pool.stats().workers[0].pid, None);
+    let _ = std::fs::remove_file(&marker);
+  }
+
+  #[test]
+  pub fn circuit_breaker_disabled_by_default() {
+    let mut pool = WorkerPool::from_source("process.exit(1);", 1).unwrap();
+    for _ in 0..CircuitBreakerPolicy::default().failure_threshold + 1 {
+      let err = pool.run_worker("double", 1).try_join().unwrap_err();
+      assert!(matches!(
+        err.downcast_ref::<WorkerError>(),
+        Some(WorkerError::BootFailed { .. })
+      ));
+    }
+  }
+
+  #[test]
+  pub fn circuit_breaker() {
+    let marker = std::env::temp_dir().join(format!("node-workers-breaker-{}", std::process::id()));
+    let _ = std::fs::remove_file(&marker);
+    let source = format!(
+      r#"
+        if (!require('fs').existsSync({:?})) process.exit(1);
+        bridge({{ double: (n) => n * 2 }});
+      "#,
+      marker.to_string_lossy()
+    );
+    let mut pool = WorkerPool::from_source(&source, 1).unwrap();
+    pool.set_circuit_breaker(Some(CircuitBreakerPolicy {
+      failure_threshold: 2,
+      cooldown: Duration::from_millis(500),
+      ..Default::default()
+    }));
+    for _ in 0..2 {
+      let err = pool.run_worker("double", 1).try_join().unwrap_err();
+      assert!(matches!(
+        err.downcast_ref::<WorkerError>(),
+        Some(WorkerError::BootFailed { .. })
+      ));
+    }
+    let err = pool.run_worker("double", 1).try_join().unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::PoolUnavailable { failures: 2, .. })
+    ));
+
+    // a probe failing opens the circuit again
+    std::thread::sleep(Duration::from_millis(600));
+    let err = pool.run_worker("double", 1).try_join().unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::BootFailed { .. })
+    ));
+    let err = pool.run_worker("double", 1).try_join().unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::PoolUnavailable { .. })
+    ));
+
+    std::fs::write(&marker, "").unwrap();
+    std::thread::sleep(Duration::from_millis(600));
+    let result = pool.run_worker("double", 21).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(42));
+    assert!(pool.run_worker("double", 1).join().is_ok());
+    let _ = std::fs::remove_file(&marker);
+  }
+
+  #[cfg(target_os = "linux")]
+  fn is_running(pid: u32) -> bool {
+    // killed processes may stay zombies if nothing reaps them
+    std::fs::read_to_string(format!("/proc/{}/stat", pid))
+      .map(|stat| {
+        !stat
+          .rsplit(')')
+          .next()
+          .unwrap_or("")
+          .trim_start()
+          .starts_with('Z')
+      })
+      .unwrap_or(false)
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  pub fn kill_process_group() {
+    let source = r#"
+      const { spawn } = require('child_process');
+      bridge({ spawn: () => spawn('sleep', ['30']).pid });
+    "#;
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    let pid = pool
+      .run_worker("spawn", 0)
+      .get_result::<u32>()
+      .unwrap()
+      .unwrap();
+    assert!(is_running(pid));
+    let worker = pool.inner.lock().unwrap().workers[0].clone();
+    worker.lock().unwrap().terminate();
+    let start = std::time::Instant::now();
+    while is_running(pid) && start.elapsed() < Duration::from_secs(5) {
+      std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(!is_running(pid));
+  }
+
+  #[test]
+  pub fn exit_on_stdin_eof() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    pool.run_worker("ping", 0).join().unwrap();
+    let worker = pool.inner.lock().unwrap().workers[0].clone();
+    let mut worker = worker.lock().unwrap();
+    worker.stdin = None;
+    let child = worker.child.clone().unwrap();
+    let start = std::time::Instant::now();
+    let status = loop {
+      if let Some(status) = child.lock().unwrap().try_wait().unwrap() {
+        break Some(status);
+      }
+      if start.elapsed() > Duration::from_secs(5) {
+        break None;
+      }
+      std::thread::sleep(Duration::from_millis(10));
+    };
+    assert!(status.unwrap().success());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  pub fn resource_limits() {
+    let source = r#"
+      const fs = require('fs');
+      bridge({
+        spin: () => { while (true) {} },
+        openFiles: () => {
+          const fds = [];
+          try {
+            for (let i = 0; i < 200; i++) fds.push(fs.openSync(__filename, 'r'));
+          } catch (err) {}
+          fds.forEach((fd) => fs.closeSync(fd));
+          return fds.length;
+        },
+      });
+    "#;
+    let mut pool = WorkerPool::from_source(source, 1).unwrap();
+    pool.set_resource_limits(ResourceLimits {
+      cpu_time: Some(Duration::from_secs(1)),
+      open_files: Some(64),
+      max_core_dump: Some(0),
+      ..Default::default()
+    });
+    let opened = pool.run_worker("openFiles", 0).get_result::<u32>().unwrap();
+    assert!(opened.unwrap() < 64);
+
+    let start = std::time::Instant::now();
+    let err = pool.run_worker("spin", 0).try_join().unwrap_err();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::ResourceLimitExceeded {
+        resource: Resource::CpuTime,
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  pub fn runtime_command_line() {
+    let mut pool = WorkerPool::setup("worker.js", 1);
+    pool.set_resource_limits(ResourceLimits {
+      max_heap_mb: Some(64),
+      ..Default::default()
+    });
+    let args = |pool: &WorkerPool| {
+      let args = pool.inner.lock().unwrap().spawn_args().unwrap();
+      args
+        .iter()
+        .map(|arg| {
+          if arg.ends_with("bridge.cjs") {
+            "<bridge>".to_string()
+          } else {
+            arg.clone()
+          }
+        })
+        .collect::<Vec<_>>()
+    };
+    assert_eq!(
+      args(&pool),
+      vec!["node", "-r", "<bridge>", "--max-old-space-size=64"]
+    );
+    pool.set_runtime(Runtime::Deno {
+      permissions: vec!["--allow-read".into()],
+    });
+    assert_eq!(
+      args(&pool),
+      vec![
+        "deno",
+        "run",
+        "--allow-read",
+        "--preload",
+        "<bridge>",
+        "--v8-flags=--max-old-space-size=64"
+      ]
+    );
+    pool.set_runtime(Runtime::Bun);
+    pool.set_binary("/opt/bun/bin/bun");
+    assert_eq!(
+      args(&pool),
+      vec!["/opt/bun/bin/bun", "--preload", "<bridge>"]
+    );
+    pool.set_runtime(Runtime::Custom("node --no-warnings".into()));
+    assert_eq!(args(&pool), vec!["node", "--no-warnings"]);
+  }
+
+  #[test]
+  pub fn custom_runtime() {
+    let bridge = std::fs::canonicalize("dist/bridge.js").unwrap();
+    let source = format!(
+      "const {{ bridge }} = require({:?});\nbridge({{ double: (n) => n * 2 }});\n",
+      bridge
+    );
+    let mut pool = WorkerPool::from_source(&source, 1).unwrap();
+    pool.set_runtime(Runtime::Custom("node --no-warnings".into()));
+    let result = pool.run_worker("double", 21).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(42));
+  }
+
+  #[test]
+  pub fn typescript_loader() {
+    let mut pool = WorkerPool::setup("examples/worker.ts", 1);
+    pool.run_worker("ping", EmptyPayload::new()).join().unwrap();
+  }
+
+  #[cfg(unix)]
+  #[test]
+  pub fn typescript_loader_missing() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // an old node without any loader installed
+    let node = std::env::temp_dir().join(format!("node-workers-old-node-{}", std::process::id()));
+    std::fs::write(
+      &node,
+      "#!/bin/sh\necho '{\"version\":\"20.0.0\",\"tsx\":null,\"esbuildRegister\":null}'\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&node, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let mut pool = WorkerPool::setup("examples/worker.ts", 1);
+    pool.set_binary(node.to_str().unwrap());
+    let err = pool.run_worker("ping", 0).try_join().unwrap_err();
+    let _ = std::fs::remove_file(&node);
+    assert!(err.to_string().contains("node 20.0.0 can't run TypeScript"));
+    assert!(err.to_string().contains("install tsx"));
+
+    // loaders configured by the user are kept
+    pool.set_binary(&format!("{} -r esbuild-register", node.to_str().unwrap()));
+    let args = pool.inner.lock().unwrap().spawn_args().unwrap();
+    assert_eq!(args[1..3], ["-r", "esbuild-register"]);
+  }
+
+  #[test]
+  pub fn esm_worker() {
+    // imports the ESM build of the bridge, and awaits at the top level before calling it
+    let mut pool = WorkerPool::setup("examples/worker.mjs", 1);
+    let result = pool
+      .run_worker("greet", "world")
+      .get_result::<String>()
+      .unwrap();
+    assert_eq!(result, Some("hello world".into()));
+    let info = pool.bridge_info().unwrap();
+    assert_eq!(info.tasks, ["greet", "ping"]);
+  }
+
+  #[test]
+  pub fn esm_package() {
+    // a .js script in a package with "type": "module", using the global bridge
+    let mut pool = WorkerPool::setup("examples/esm/worker.js", 1);
+    let boot_time = pool
+      .run_worker("bootTime", EmptyPayload::new())
+      .get_result::<u64>()
+      .unwrap()
+      .unwrap();
+    assert!(boot_time >= 100);
+    let result = pool.run_worker("double", 21).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(42));
+  }
+
+  #[test]
+  pub fn node_version_requirement() {
+    let mut pool = WorkerPool::setup("examples/worker.js", 1);
+    pool.require_node_version(">=14").unwrap();
+    pool.run_worker("fib2", 10).join().unwrap();
+
+    let err = pool.require_node_version("<14").unwrap_err();
+    match err.downcast_ref::<WorkerError>() {
+      Some(WorkerError::UnsupportedNode {
+        found, required, ..
+      }) => {
+        let version = std::process::Command::new("node")
+          .arg("--version")
+          .output()
+          .unwrap();
+        assert_eq!(
+          format!("v{}", found),
+          String::from_utf8_lossy(&version.stdout).trim()
+        );
+        assert_eq!(required, "<14");
+      }
+      _ => panic!("unexpected error: {:?}", err),
+    }
+    assert!(pool.require_node_version("not a version").is_err());
+    // requirements that failed aren't applied
+    pool.run_worker("fib2", 10).join().unwrap();
+  }
+
+  #[test]
+  pub fn worker_path_binary() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    let binary = |pool: &WorkerPool| pool.inner.lock().unwrap().binary_args.to_vec();
+    pool.set_binary("node --no-warnings");
+    drop(pool.set_worker_path("examples/worker.js"));
+    assert_eq!(binary(&pool), vec!["node", "--no-warnings"]);
+
+    // the runtime resets the binary, which is then discovered for each script
+    pool.set_runtime(Runtime::Node);
+    drop(pool.set_worker_path("examples/worker"));
+    assert_eq!(binary(&pool), vec![node_binary::resolve("examples/worker")]);
+  }
+
+  #[cfg(unix)]
+  #[test]
+  pub fn node_version_checked_before_spawning() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let node = std::env::temp_dir().join(format!("node-workers-node-16-{}", std::process::id()));
+    std::fs::write(&node, "#!/bin/sh\necho v16.20.2\n").unwrap();
+    std::fs::set_permissions(&node, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let mut pool = WorkerPool::setup("examples/worker.js", 1);
+    pool.require_node_version(">=18").unwrap();
+    // the binary is changed after the requirement was checked
+    pool.set_binary(node.to_str().unwrap());
+    let err = pool.run_worker("fib2", 10).try_join().unwrap_err();
+    let _ = std::fs::remove_file(&node);
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::UnsupportedNode { found, .. }) if found == "16.20.2"
+    ));
+  }
+
+  /// Run a worker with the global bridge on a runtime
+  fn run_on(runtime: Runtime) {
+    let mut pool = WorkerPool::from_source("bridge({ double: (n) => n * 2 });", 1).unwrap();
+    pool.set_runtime(runtime);
+    let result = pool.run_worker("double", 21).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(42));
+  }
+
+  #[test]
+  #[ignore = "requires deno 2.4 or later"]
+  pub fn deno_runtime() {
+    run_on(Runtime::Deno {
+      permissions: vec![],
+    });
+  }
+
+  #[test]
+  #[ignore = "requires bun"]
+  pub fn bun_runtime() {
+    run_on(Runtime::Bun);
+  }
+
+  #[test]
+  pub fn perform_iter() {
+    let mut pool = WorkerPool::setup("examples/worker", 3);
+    let results = pool
+      .perform_iter::<u32, _>("sleep", vec![400, 10, 200])
+      .map(|(n, res)| (n, res.unwrap()))
+      .collect::<Vec<_>>();
+    assert_eq!(results, vec![(1, Some(10)), (2, Some(200)), (0, Some(400))]);
+
+    // payloads are submitted as the queue has room for them
+    pool.set_queue_capacity(1);
+    pool.set_queue_policy(QueuePolicy::FailFast);
+    let iter = pool.perform_iter::<u32, _>("fib2", vec![10, 11, 12, 13, 14, 15]);
+    assert_eq!(iter.len(), 6);
+    let mut results = iter
+      .map(|(n, res)| (n, res.unwrap().unwrap()))
+      .collect::<Vec<_>>();
+    results.sort();
+    assert_eq!(
+      results,
+      vec![(0, 89), (1, 144), (2, 233), (3, 377), (4, 610), (5, 987)]
+    );
+  }
+
+  #[test]
+  pub fn perform_iter_rejections() {
+    // the iterator doesn't submit tasks to an unavailable pool
+    let mut pool = WorkerPool::from_source("process.exit(1);", 1).unwrap();
+    pool.set_circuit_breaker(Some(CircuitBreakerPolicy {
+      failure_threshold: 1,
+      cooldown: Duration::from_secs(60),
+      ..Default::default()
+    }));
+    assert!(pool.run_worker("double", 1).join().is_err());
+    for (_, res) in pool.perform_iter::<u32, _>("double", vec![1, 2]) {
+      assert!(matches!(
+        res.unwrap_err().downcast_ref::<WorkerError>(),
+        Some(WorkerError::PoolUnavailable { .. })
+      ));
+    }
+
+    // nor to a pool that was dropped
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    let iter = pool.perform_iter::<u32, _>("fib2", vec![10, 11]);
+    drop(pool);
+    let results = iter.collect::<Vec<_>>();
+    assert_eq!(results.len(), 2);
+    for (_, res) in results {
+      assert_eq!(
+        res.unwrap_err().downcast_ref::<WorkerError>(),
+        Some(&WorkerError::PoolClosed)
+      );
+    }
+  }
+
+  #[test]
+  pub fn perform_errors() {
+    let mut pool = WorkerPool::setup("examples/worker", 2);
+    let err = pool
+      .perform::<u32, _>("getUser", EmptyPayload::bulk(2))
+      .unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::Deserialize { .. })
+    ));
+    let err = pool.perform::<u32, _>("foo", vec![1]).unwrap_err();
+    assert!(matches!(
+      err.downcast_ref::<WorkerError>(),
+      Some(WorkerError::TaskNotFound { .. })
+    ));
+  }
+
   #[test]
   pub fn error_invalid_command() {
     {
@@ -277,6 +1982,29 @@ mod tests {
     }
   }
 
+  #[test]
+  pub fn task_not_found_keeps_worker() {
+    let mut pool = WorkerPool::setup("examples/worker", 1);
+    // the tasks of the worker aren't known yet, so the task reaches the worker
+    let err = pool.run_worker("no", 40).try_join().unwrap_err();
+    match err.downcast_ref::<WorkerError>() {
+      Some(WorkerError::TaskNotFound { command, available }) => {
+        assert_eq!(command, "no");
+        assert!(available.contains(&"fib2".to_string()));
+      }
+      _ => panic!("unexpected error: {:?}", err),
+    }
+    let pid = pool.stats().workers[0].pid;
+    assert!(pid.is_some());
+
+    let result = pool.run_worker("fib2", 10).get_result::<u32>().unwrap();
+    assert_eq!(result, Some(89));
+    let stats = pool.stats();
+    assert_eq!(stats.workers.len(), 1);
+    assert_eq!(stats.workers[0].pid, pid);
+    assert_eq!(stats.workers[0].tasks_done, 1);
+  }
+
   #[test]
   pub fn error_task_not_found() {
     {
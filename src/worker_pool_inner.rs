@@ -1,20 +1,104 @@
 use anyhow::{bail, Result};
+use semver::VersionReq;
 
-use crate::{print_debug, worker::Worker, worker_thread::WorkerThread, AsPayload};
-use std::sync::{
-  atomic::{AtomicUsize, Ordering},
-  Arc, Mutex,
+use crate::{
+  bridge::{self, SharedBridgeInfo},
+  circuit_breaker::CircuitBreaker,
+  error::WorkerError,
+  managed_file::ManagedFile,
+  node_binary, print_debug,
+  process_limit::ProcessLimit,
+  resource_limits::ResourceLimits,
+  retry::RetryPolicy,
+  runtime::Runtime,
+  stats::PoolMetrics,
+  task_queue::Task,
+  typescript::{self, DetectedLoader},
+  worker::Worker,
+  worker_thread::resolve,
 };
+use std::{
+  sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Condvar, Mutex,
+  },
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
+
+/// How long the dispatcher waits for a notification before looking for an idle worker again,
+/// in case the worker it missed was locked by another thread
+const AVAILABILITY_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Notified whenever a worker may have become available, so that the dispatcher can sleep while every worker is busy
+#[derive(Debug, Default)]
+pub(crate) struct WorkerAvailability {
+  notifications: Mutex<u64>,
+  available: Condvar,
+}
+
+impl WorkerAvailability {
+  pub fn notify(&self) {
+    *self.notifications.lock().unwrap() += 1;
+    self.available.notify_all();
+  }
+
+  /// Number of notifications so far, to be given to `wait`
+  pub fn current(&self) -> u64 {
+    *self.notifications.lock().unwrap()
+  }
+
+  /// Wait for a notification following `current`, or for `AVAILABILITY_CHECK_INTERVAL`
+  pub fn wait(&self, current: u64) {
+    let notifications = self.notifications.lock().unwrap();
+    let _ = self
+      .available
+      .wait_timeout_while(notifications, AVAILABILITY_CHECK_INTERVAL, |n| {
+        *n == current
+      })
+      .unwrap();
+  }
+}
 
 /// Struct responsible of the inner working of the pool
 /// Needs to be wrapped in a Arc<Mutex<T>> for manipulations within different threads
 pub struct WorkerPoolInner {
   pub worker_path: Arc<str>,
   pub binary_args: Arc<Vec<String>>,
+  /// JS runtime of the workers, see `WorkerPool::set_runtime`
+  pub runtime: Runtime,
   pub workers: Vec<Arc<Mutex<Worker>>>,
   pub max_workers: usize,
   pub busy_counter: Arc<AtomicUsize>,
+  /// Notified when a worker goes idle, see `wait_for_worker`
+  pub(crate) availability: Arc<WorkerAvailability>,
+  /// Incremented when the worker script changes. Processes spawned before that are replaced.
+  pub generation: Arc<AtomicUsize>,
+  pub metrics: Arc<PoolMetrics>,
+  /// Bridge info reported by the latest worker that became ready
+  pub info: SharedBridgeInfo,
+  /// How long a worker has to get ready once spawned, see `WorkerPool::set_boot_timeout`
+  pub boot_timeout: Option<Duration>,
+  /// Limits applied to the processes of new workers, see `WorkerPool::set_resource_limits`
+  pub resources: Option<Arc<ResourceLimits>>,
+  /// How failed tasks are retried, see `WorkerPool::set_retry_policy`
+  pub retry: Arc<RetryPolicy>,
   pub debug: bool,
+  /// Stops spawning workers when they keep failing to boot, see `WorkerPool::set_circuit_breaker`
+  pub(crate) breaker: Arc<CircuitBreaker>,
+  /// Limit on running processes shared with other pools, see `WorkerManager`
+  pub(crate) limit: Option<Arc<ProcessLimit>>,
+  /// Worker script written from the source given to `WorkerPool::from_source`
+  pub(crate) source: Option<ManagedFile>,
+  /// Whether the embedded bridge is preloaded in workers, see `WorkerPool::set_bridge_preload`
+  pub preload_bridge: bool,
+  preload: Option<Arc<ManagedFile>>,
+  /// Arguments loading a TypeScript worker script, see `typescript_loader`
+  ts_loader: Option<DetectedLoader>,
+  /// Version of node the workers require, see `WorkerPool::require_node_version`
+  pub node_version: Option<VersionReq>,
+  /// Whether the binary was given with `set_binary`, rather than resolved from the worker script
+  custom_binary: bool,
 }
 
 impl WorkerPoolInner {
@@ -22,17 +106,45 @@ impl WorkerPoolInner {
   pub fn setup(worker_path: &str, max_workers: usize) -> Self {
     WorkerPoolInner {
       worker_path: worker_path.into(),
-      binary_args: Arc::new(vec!["node".into()]),
+      binary_args: Arc::new(vec![node_binary::resolve(worker_path)]),
+      runtime: Runtime::Node,
       workers: Vec::new(),
       max_workers,
       busy_counter: Arc::new(AtomicUsize::new(0)),
+      availability: Default::default(),
+      generation: Arc::new(AtomicUsize::new(0)),
+      metrics: Arc::new(PoolMetrics::default()),
+      info: Default::default(),
+      boot_timeout: Some(Duration::from_secs(30)),
+      resources: None,
+      retry: Default::default(),
       debug: false,
+      breaker: Arc::new(CircuitBreaker::new(None)),
+      limit: None,
+      source: None,
+      preload_bridge: true,
+      preload: None,
+      ts_loader: None,
+      node_version: None,
+      custom_binary: false,
     }
   }
 
   /// Refers to `WorkerPool::set_binary` for documentation
   pub fn set_binary(&mut self, binary: &str) {
     self.binary_args = Arc::new(shell_words::split(binary).expect("couldn't parse binary"));
+    self.custom_binary = true;
+  }
+
+  /// Refers to `WorkerPool::set_worker_path` for documentation.
+  /// The node binary is resolved again for the new script, unless it was given with `set_binary`.
+  pub fn set_worker_path(&mut self, worker_path: &str) {
+    self.worker_path = worker_path.into();
+    if self.runtime == Runtime::Node && !self.custom_binary {
+      self.binary_args = Arc::new(vec![node_binary::resolve(worker_path)]);
+    }
+    // the new script may define other tasks, they are known again once a worker running it is ready
+    *self.info.lock().unwrap() = None;
   }
 
   /// Refers to `WorkerPool::with_debug` for documentation
@@ -40,97 +152,392 @@ impl WorkerPoolInner {
     self.debug = debug;
   }
 
-  /// Run a worker in a new thread. However, `get_available_worker` is executed on the main thread
+  /// Refers to `WorkerPool::set_runtime` for documentation
+  pub fn set_runtime(&mut self, runtime: Runtime) {
+    self.binary_args = Arc::new(match runtime {
+      Runtime::Node => vec![node_binary::resolve(&self.worker_path)],
+      _ => runtime.binary(),
+    });
+    self.runtime = runtime;
+    self.custom_binary = false;
+  }
+
+  /// Arguments of the command spawning a worker, followed by the path of the worker script.
+  /// Includes the preloading of the embedded bridge, unless it's disabled or not supported by the runtime.
+  pub fn spawn_args(&mut self) -> Result<Arc<Vec<String>>> {
+    let preload = if self.preload_bridge && self.runtime.preloads_bridge() {
+      Some(match &self.preload {
+        Some(preload) => preload.clone(),
+        None => self.preload.insert(bridge::preload_file()?).clone(),
+      })
+    } else {
+      None
+    };
+    self.check_node_version()?;
+    let max_heap_mb = self.resources.as_ref().and_then(|r| r.max_heap_mb);
+    let mut args = self.binary_args.to_vec();
+    args.extend(
+      self
+        .runtime
+        .args(preload.as_ref().map(|p| p.path()), max_heap_mb),
+    );
+    args.extend(self.typescript_loader()?);
+    Ok(Arc::new(args))
+  }
+
+  /// Refers to `WorkerPool::require_node_version` for documentation
+  pub fn require_node_version(&mut self, requirement: VersionReq) -> Result<()> {
+    if self.runtime == Runtime::Node {
+      node_binary::check(&self.binary_args[0], &requirement)?;
+    }
+    self.node_version = Some(requirement);
+    Ok(())
+  }
+
+  /// Check the binary against the version required with `WorkerPool::require_node_version`.
+  /// The version of each binary is cached, so this is only slow the first time.
+  pub fn check_node_version(&self) -> Result<()> {
+    match &self.node_version {
+      Some(requirement) if self.runtime == Runtime::Node => {
+        node_binary::check(&self.binary_args[0], requirement)
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Arguments letting node run the worker script if it's written in TypeScript, unless the binary already loads something.
+  /// Other runtimes support TypeScript out of the box.
+  fn typescript_loader(&mut self) -> Result<Vec<String>> {
+    if self.runtime != Runtime::Node
+      || !typescript::is_typescript(&self.worker_path)
+      || typescript::has_loader(&self.binary_args)
+    {
+      return Ok(Vec::new());
+    }
+    let node = &self.binary_args[0];
+    if let Some(loader) = &self.ts_loader {
+      if loader.node == *node && loader.worker_path == self.worker_path {
+        return Ok(loader.args.clone());
+      }
+    }
+    let args = typescript::loader_args(node, &self.worker_path)?;
+    print_debug!(self.debug, "[pool] loading TypeScript with {:?}", args);
+    self.ts_loader = Some(DetectedLoader {
+      node: node.clone(),
+      worker_path: self.worker_path.clone(),
+      args: args.clone(),
+    });
+    Ok(args)
+  }
+
+  /// Replace the processes of every worker so that they run the latest version of the worker script.
+  /// Idle processes are killed right away and busy ones once their task is done. Workers spawn a new process on next use.
+  pub fn reload(&mut self) {
+    self.generation.fetch_add(1, Ordering::SeqCst);
+    print_debug!(self.debug, "[pool] reloading workers");
+    // the new script may define other tasks, they are known again once a worker running it is ready
+    *self.info.lock().unwrap() = None;
+    for worker in &self.workers {
+      if let Ok(mut worker) = worker.try_lock() {
+        if worker.idle {
+          worker.terminate();
+        }
+      }
+    }
+  }
+
+  /// Run a worker in a new thread, bypassing the queue of the pool. However, `get_available_worker` is executed on the main thread
   /// and therefor can block if the pool is waiting for an idle worker.
-  pub fn run_worker<P: AsPayload>(&mut self, cmd: String, payload: P) -> WorkerThread {
+  #[cfg(test)]
+  pub fn run_worker<P: crate::AsPayload>(
+    &mut self,
+    cmd: String,
+    payload: P,
+  ) -> crate::worker_thread::WorkerThread {
+    let (sender, receiver) = std::sync::mpsc::channel();
+    let task = Task::with_options(cmd, payload.to_payload(), Default::default(), sender);
+    let canceller = crate::worker_thread::CancelHandle::new(
+      task.control.clone(),
+      std::sync::Weak::new(),
+      std::time::Duration::ZERO,
+    );
     let worker = self.get_available_worker();
+    self.run_task(worker, task);
+    crate::worker_thread::WorkerThread::from_receiver(receiver, canceller)
+  }
+
+  /// Perform a task on a worker previously obtained with `get_available_worker`, in a new thread.
+  /// The result is sent back through the task's channel.
+  pub(crate) fn run_task(&mut self, worker: Arc<Mutex<Worker>>, task: Task) {
+    let binary_args = match self.spawn_args() {
+      Ok(args) => args,
+      Err(err) => {
+        worker.lock().unwrap().idle = true;
+        self.availability.notify();
+        resolve(&task.sender, Err(err));
+        return;
+      }
+    };
     self.busy_counter.fetch_add(1, Ordering::SeqCst);
 
-    print_debug!(
-      self.debug,
-      "[pool] got worker {}",
-      worker.lock().unwrap().id
-    );
+    let worker_id = worker.lock().unwrap().id;
+    print_debug!(self.debug, "[pool] got worker {}", worker_id);
+    self
+      .metrics
+      .task_started(worker_id, task.queued_at.elapsed());
     let waiting = self.busy_counter.clone();
+    let metrics = self.metrics.clone();
     let debug = self.debug;
-    let binary_args = self.binary_args.clone();
-    let payload = payload.to_payload();
     let file_path = self.worker_path.clone();
+    let generation = self.generation.clone();
+    let current_generation = generation.load(Ordering::SeqCst);
+    let retry = self.retry.clone();
+    let availability = self.availability.clone();
 
-    let handle = std::thread::spawn(move || {
-      let worker = worker.clone();
+    std::thread::spawn(move || {
       let mut worker = worker.lock().unwrap();
-      worker.init(binary_args, file_path).unwrap();
-      let res = worker.perform_task(cmd, payload).expect("perform task");
-      print_debug!(debug, "[pool] performed task on worker {}", worker.id);
+      let started_at = Instant::now();
+      let Task {
+        cmd,
+        payload,
+        control,
+        sender,
+        ..
+      } = task;
+      let mut attempt = 0;
+      let res = loop {
+        attempt += 1;
+        let res = init_worker(
+          &mut worker,
+          binary_args.clone(),
+          file_path.clone(),
+          current_generation,
+          &metrics,
+        )
+        .and_then(|_| {
+          if !control.start(worker.id, worker.process()) {
+            bail!(WorkerError::Cancelled);
+          }
+          worker.perform_task(cmd.clone(), payload.clone())
+        });
+        let delay = match &res {
+          Err(err) if !control.is_cancelled() => {
+            retry.retry_delay(&cmd, attempt, err, worker.child.is_some())
+          }
+          _ => None,
+        };
+        match delay {
+          None if attempt > 1 => {
+            break res.map_err(|err| err.context(format!("task failed after {} attempts", attempt)))
+          }
+          None => break res,
+          Some(delay) => {
+            print_debug!(
+              debug,
+              "[pool] attempt {} of task failed on worker {}, retrying in {:?}",
+              attempt,
+              worker.id,
+              delay
+            );
+            worker.terminate();
+            std::thread::sleep(delay);
+            // the task may have been cancelled while waiting, no need for a new process then
+            if control.is_cancelled() {
+              break Err(WorkerError::Cancelled.into());
+            }
+          }
+        }
+      };
+      let cancelled = control.finish();
+      // a task that stopped after receiving the cancel frame, or that wasn't found, leaves the worker usable
+      let healthy = match &res {
+        Ok(_) => true,
+        Err(err) => matches!(
+          err.downcast_ref::<WorkerError>(),
+          Some(WorkerError::Cancelled | WorkerError::TaskNotFound { .. })
+        ),
+      };
+      metrics.task_finished(
+        worker.id,
+        &cmd,
+        started_at.elapsed(),
+        res.is_ok() && !cancelled,
+      );
+      if res.is_ok() {
+        print_debug!(debug, "[pool] performed task on worker {}", worker.id);
+      } else {
+        print_debug!(debug, "[pool] task failed on worker {}", worker.id);
+      }
+      if !healthy {
+        worker.terminate();
+      }
+
+      let res = if cancelled {
+        Err(WorkerError::Cancelled.into())
+      } else {
+        res
+      };
+      resolve(&sender, res);
+
+      if cancelled && !healthy {
+        // the worker was killed because it didn't stop in time, replace it right away
+        print_debug!(debug, "[pool] respawning worker {}", worker.id);
+        let respawned = init_worker(
+          &mut worker,
+          binary_args,
+          file_path,
+          current_generation,
+          &metrics,
+        )
+        .and_then(|_| worker.wait_for_ready());
+        match respawned {
+          Ok(_) => metrics.worker_ready(worker.id),
+          Err(_) => worker.terminate(),
+        }
+      }
+      if worker.generation != generation.load(Ordering::SeqCst) {
+        print_debug!(debug, "[pool] worker {} is outdated", worker.id);
+        worker.terminate();
+      }
+      worker.idle = true;
       drop(worker);
 
       waiting.fetch_sub(1, Ordering::SeqCst);
-      res
+      availability.notify();
     });
-    WorkerThread::from_handle(handle)
   }
 
-  /// Find an idle worker that can take on a task.
+  /// Find an idle worker that can take on a task, and mark it as busy.
   /// If no worker is free, and the capacity of the pool is not reached yet, a new worker is created.
-  /// However, if the capacity is reached, this method will wait (and block) until a worker is idle.
-  pub fn get_available_worker(&mut self) -> Arc<Mutex<Worker>> {
-    let idle_worker = self.workers.iter().find(|w| {
-      if let Ok(w) = w.try_lock() {
-        return w.idle;
+  /// Workers locked by another thread are skipped, whoever holds them notifies `availability` once done.
+  pub fn find_available_worker(&mut self) -> Option<Arc<Mutex<Worker>>> {
+    for worker in &self.workers {
+      if let std::result::Result::Ok(mut w) = worker.try_lock() {
+        if w.idle {
+          w.idle = false;
+          print_debug!(self.debug, "[pool] found idle worker");
+          return Some(worker.clone());
+        }
       }
-      false
-    });
-    if let Some(idle_worker) = idle_worker {
-      idle_worker.lock().unwrap().idle = false;
-      print_debug!(self.debug, "[pool] found idle worker");
-      return idle_worker.clone();
     }
     if self.workers.len() < self.max_workers {
-      let mut worker = Worker::new(self.workers.len() + 1, self.debug);
-      worker.idle = false;
-      self.workers.push(Arc::new(Mutex::new(worker)));
+      let worker = self.new_worker();
+      worker.lock().unwrap().idle = false;
       print_debug!(self.debug, "[pool] created new worker");
-      return self.workers.last().unwrap().clone();
+      return Some(worker);
     }
-    print_debug!(self.debug, "[pool] waiting for worker to be free");
+    None
+  }
+
+  /// Same as `wait_for_worker`, but holds the pool while waiting
+  #[cfg(test)]
+  pub fn get_available_worker(&mut self) -> Arc<Mutex<Worker>> {
+    let availability = self.availability.clone();
     loop {
-      if self.busy_counter.load(Ordering::SeqCst) == 0 {
-        print_debug!(self.debug, "[pool] pool is free");
-        break;
+      let current = availability.current();
+      if let Some(worker) = self.find_available_worker() {
+        return worker;
       }
+      availability.wait(current);
     }
-    self.get_available_worker()
   }
 
-  pub fn warmup(&mut self, nbr_workers: usize) -> Result<()> {
+  /// Add a worker to the pool. Its process is spawned on first use.
+  pub(crate) fn new_worker(&mut self) -> Arc<Mutex<Worker>> {
+    let mut worker = self.detached_worker(self.workers.len() + 1);
+    worker.metrics = Some(self.metrics.clone());
+    self.metrics.worker_created(worker.id);
+    let worker = Arc::new(Mutex::new(worker));
+    if let Some(limit) = &self.limit {
+      limit.register(&worker);
+    }
+    self.workers.push(worker.clone());
+    worker
+  }
+
+  /// Create a worker configured like the ones of the pool, without adding it to the pool.
+  /// Its id is the one of the worker whose process it's going to replace, see `rolling_restart`.
+  pub(crate) fn detached_worker(&self, id: usize) -> Worker {
+    let mut worker = Worker::new(id, self.debug);
+    worker.limit = self.limit.clone();
+    worker.breaker = Some(self.breaker.clone());
+    worker.boot_timeout = self.boot_timeout;
+    worker.resources = self.resources.clone();
+    worker.pool_info = Some(self.info.clone());
+    worker
+  }
+
+  /// Create up to `nbr_workers` workers, and boot them in new threads.
+  /// The threads don't need the pool, so it can be released while they're joined.
+  pub fn warmup(&mut self, nbr_workers: usize) -> Result<Vec<JoinHandle<Result<()>>>> {
+    let binary_args = self.spawn_args()?;
     let n = nbr_workers.clamp(0, self.max_workers - self.workers.len());
     let debug = self.debug;
-    let ln = self.workers.len();
     let mut handles = Vec::new();
-    for n in 0..n {
-      let id = ln + n + 1;
-      let worker = Worker::new(id, debug);
-      let mutex = Arc::new(Mutex::new(worker));
-      self.workers.push(mutex.clone());
+    for _ in 0..n {
+      let mutex = self.new_worker();
       print_debug!(debug, "[pool] (warmup) created new worker");
 
-      let binary_args = self.binary_args.clone();
+      let binary_args = binary_args.clone();
       let file_path = self.worker_path.clone();
+      let metrics = self.metrics.clone();
+      let availability = self.availability.clone();
+      let generation = self.generation.load(Ordering::SeqCst);
       let handle = std::thread::spawn(move || {
-        let worker = mutex.clone();
-        let mut worker = worker.lock().unwrap();
-        worker.init(binary_args, file_path).unwrap();
-        worker.wait_for_ready().unwrap();
-        print_debug!(debug, "[pool] (warmup) worker {} initialized", id);
+        let mut worker = mutex.lock().unwrap();
+        let id = worker.id;
+        let res = init_worker(&mut worker, binary_args, file_path, generation, &metrics)
+          .and_then(|_| worker.wait_for_ready());
+        if res.is_err() {
+          worker.terminate();
+        } else {
+          metrics.worker_ready(id);
+          print_debug!(debug, "[pool] (warmup) worker {} initialized", id);
+        }
+        drop(worker);
+        availability.notify();
+        res
       });
       handles.push(handle);
     }
-    for handle in handles {
-      if handle.join().is_err() {
-        bail!("thread panicked")
-      }
+    Ok(handles)
+  }
+}
+
+/// Wait for a worker to take on a task, and mark it as busy.
+/// The pool is only locked while looking for a worker, so that it can be used while every worker is busy,
+/// and by threads that lock a worker before the pool.
+pub(crate) fn wait_for_worker(inner: &Mutex<WorkerPoolInner>) -> Arc<Mutex<Worker>> {
+  loop {
+    let mut pool = inner.lock().unwrap();
+    let availability = pool.availability.clone();
+    let current = availability.current();
+    if let Some(worker) = pool.find_available_worker() {
+      return worker;
     }
-    Ok(())
+    print_debug!(pool.debug, "[pool] waiting for worker to be free");
+    drop(pool);
+    availability.wait(current);
+  }
+}
+
+/// Spawn the worker's process if needed, and record it in the pool metrics
+fn init_worker(
+  worker: &mut Worker,
+  binary_args: Arc<Vec<String>>,
+  file_path: Arc<str>,
+  generation: usize,
+  metrics: &PoolMetrics,
+) -> Result<()> {
+  // the process may have missed a reload, while it was locked
+  if worker.child.is_some() && worker.generation != generation {
+    worker.terminate();
+  }
+  if worker.child.is_some() {
+    return Ok(());
   }
+  worker.init(binary_args, file_path)?;
+  worker.generation = generation;
+  metrics.worker_spawned(worker.id, worker.pid());
+  Ok(())
 }
@@ -1,36 +1,350 @@
-use std::thread::JoinHandle;
+use std::{
+  any::Any,
+  sync::{
+    mpsc::{Receiver, RecvTimeoutError, Sender},
+    Arc, Condvar, Mutex, Weak,
+  },
+  thread::JoinHandle,
+  time::{Duration, Instant},
+};
 
-use anyhow::{bail, Ok, Result};
+use anyhow::{anyhow, bail, Ok, Result};
 use serde::de::DeserializeOwned;
 
-/// Wraps a `std::thread::JoinHandle` for convenience
+use crate::{
+  error::WorkerError,
+  task_queue::{Cancellation, TaskControl, TaskQueue},
+};
+
+/// Incremented every time a task of any pool resolves, so that `wait_any` can sleep until something happens
+static COMPLETIONS: Mutex<u64> = Mutex::new(0);
+static COMPLETED: Condvar = Condvar::new();
+
+/// How often handles created with `WorkerThread::from_handle` are checked, as they don't notify their completion
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Send the result of a task to its handle
+pub(crate) fn resolve(sender: &Sender<Result<Option<String>>>, result: Result<Option<String>>) {
+  let _ = sender.send(result);
+  *COMPLETIONS.lock().unwrap() += 1;
+  COMPLETED.notify_all();
+}
+
+/// Lets you cancel a task from any thread. Obtained with `WorkerThread::cancel_handle`.
+#[derive(Debug, Clone)]
+pub struct CancelHandle {
+  control: Arc<TaskControl>,
+  queue: Weak<TaskQueue>,
+  timeout: Duration,
+}
+
+impl CancelHandle {
+  pub(crate) fn new(control: Arc<TaskControl>, queue: Weak<TaskQueue>, timeout: Duration) -> Self {
+    CancelHandle {
+      control,
+      queue,
+      timeout,
+    }
+  }
+
+  /// Cancel the task, which then resolves with `WorkerError::Cancelled`.
+  /// A queued task is removed from the queue. For a running task, the worker is sent a cancel frame which aborts
+  /// the `AbortSignal` given to the JS task. If the task is still running after the cancel timeout of the pool,
+  /// the worker is killed and respawned.
+  ///
+  /// Returns false if the task already completed or was already cancelled.
+  pub fn cancel(&self) -> bool {
+    match self.control.cancel() {
+      Cancellation::Noop => false,
+      Cancellation::Queued => {
+        // if the task isn't in the queue anymore, the pool is about to run it and will notice it was cancelled
+        if let Some(task) = self.queue.upgrade().and_then(|q| q.remove(self.control.id)) {
+          task.reject(WorkerError::Cancelled);
+        }
+        true
+      }
+      Cancellation::Running(process) => {
+        let control = self.control.clone();
+        let timeout = self.timeout;
+        std::thread::spawn(move || {
+          if !control.wait_finished(timeout) {
+            process.kill();
+          }
+        });
+        true
+      }
+    }
+  }
+}
+
+#[derive(Debug)]
+enum Inner {
+  Thread(JoinHandle<Option<String>>),
+  Task(Receiver<Result<Option<String>>>),
+  /// The task is finished and its result is waiting to be retrieved
+  Finished(Result<Option<String>>),
+  /// The result was retrieved with `try_get_result` or `get_result_timeout`
+  Taken,
+}
+
+fn join_thread(handle: JoinHandle<Option<String>>) -> Result<Option<String>> {
+  match handle.join() {
+    std::thread::Result::Ok(res) => Ok(res),
+    std::thread::Result::Err(_) => bail!("thread panicked"),
+  }
+}
+
+/// Maximum length of the raw result included in deserialization errors
+const RAW_RESULT_PREVIEW: usize = 200;
+
+fn parse_result<R: DeserializeOwned>(
+  result: Option<String>,
+  canceller: Option<&CancelHandle>,
+) -> Result<Option<R>> {
+  let raw = match result {
+    Some(raw) => raw,
+    None => return Ok(None),
+  };
+  let de = &mut serde_json::Deserializer::from_str(&raw);
+  match serde_path_to_error::deserialize::<_, R>(de) {
+    std::result::Result::Ok(res) => Ok(Some(res)),
+    Err(err) => {
+      let mut preview = raw.chars().take(RAW_RESULT_PREVIEW).collect::<String>();
+      if preview.len() < raw.len() {
+        preview.push_str("...");
+      }
+      bail!(WorkerError::Deserialize {
+        command: canceller.map(|c| c.control.cmd.clone()),
+        worker: canceller.and_then(|c| c.control.worker()),
+        path: err.path().to_string(),
+        message: err.into_inner().to_string(),
+        raw: preview,
+      })
+    }
+  }
+}
+
+/// Handle to a task performed by the pool.
+/// It either wraps a `std::thread::JoinHandle`, or the receiving end of a task submitted to the pool queue.
 #[derive(Debug)]
 pub struct WorkerThread {
-  inner: JoinHandle<Option<String>>,
+  inner: Inner,
+  canceller: Option<CancelHandle>,
 }
 impl WorkerThread {
   /// Create a wrapper arround an existing handle. That handle should return `Option<String>`
   pub fn from_handle(handle: JoinHandle<Option<String>>) -> WorkerThread {
-    WorkerThread { inner: handle }
+    WorkerThread {
+      inner: Inner::Thread(handle),
+      canceller: None,
+    }
   }
 
-  /// `join()` the inner handle
+  /// Create a handle that resolves when the pool sends the result of the task
+  pub(crate) fn from_receiver(
+    receiver: Receiver<Result<Option<String>>>,
+    canceller: CancelHandle,
+  ) -> WorkerThread {
+    WorkerThread {
+      inner: Inner::Task(receiver),
+      canceller: Some(canceller),
+    }
+  }
+
+  /// Cancel the task. Refer to `CancelHandle::cancel` for details.
+  /// Always returns false for handles created with `from_handle`.
+  /// ```
+  /// use node_workers::{WorkerError, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// let thread = pool.run_worker("fib2", 40u32);
+  /// thread.cancel();
+  /// let err = thread.try_join().unwrap_err();
+  /// assert_eq!(err.downcast_ref::<WorkerError>(), Some(&WorkerError::Cancelled));
+  /// ```
+  pub fn cancel(&self) -> bool {
+    self
+      .canceller
+      .as_ref()
+      .map(|canceller| canceller.cancel())
+      .unwrap_or(false)
+  }
+
+  /// Number of times the task was started on a worker so far, which is more than one if it was retried
+  /// (see `WorkerPool::set_retry_policy`). Always 0 for handles created with `from_handle`.
+  pub fn attempts(&self) -> u32 {
+    self
+      .canceller
+      .as_ref()
+      .map(|canceller| canceller.control.attempts())
+      .unwrap_or(0)
+  }
+
+  /// Get a handle that can cancel the task from another thread, while this one is joined
+  pub fn cancel_handle(&self) -> Option<CancelHandle> {
+    self.canceller.clone()
+  }
+
+  /// Wait for the task to finish and get its serialized result.
+  /// The error of a failed task is boxed, use `try_join` to inspect it.
+  ///
+  /// ## Errors
+  ///
+  /// Will return an error variant if the task failed, or if the thread panicked during `join()`.
   pub fn join(self) -> std::thread::Result<Option<String>> {
-    self.inner.join()
+    self
+      .try_join()
+      .map_err(|err| Box::new(err) as Box<dyn Any + Send>)
+  }
+
+  /// Wait for the task to finish and get its serialized result, or the error of the task such as a `WorkerError`.
+  /// ```
+  /// use node_workers::{WorkerError, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// let err = pool.run_worker("no", 1).try_join().unwrap_err();
+  /// assert!(matches!(
+  ///   err.downcast_ref::<WorkerError>(),
+  ///   Some(WorkerError::TaskNotFound { .. })
+  /// ));
+  /// ```
+  ///
+  /// ## Errors
+  ///
+  /// Will return an error variant if the task failed, or if the thread panicked.
+  pub fn try_join(self) -> Result<Option<String>> {
+    match self.inner {
+      Inner::Thread(handle) => join_thread(handle),
+      Inner::Task(receiver) => receiver
+        .recv()
+        .map_err(|_| anyhow!("task was abandoned by the pool"))?,
+      Inner::Finished(res) => res,
+      Inner::Taken => bail!("result was already retrieved"),
+    }
   }
 
   /// Join the handle and deserialize it's result.
   ///
   /// ## Errors
   ///
-  /// Whill return an error variant if the thread panicked during `join()`.
+  /// Whill return an error variant if the task failed or the thread panicked during `join()`,
+  /// or `WorkerError::Deserialize` if the result doesn't match `R`.
+  /// ```
+  /// use node_workers::{EmptyPayload, WorkerError, WorkerPool};
+  ///
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// let err = pool.run_worker("getUser", EmptyPayload::new()).get_result::<u32>().unwrap_err();
+  /// assert!(matches!(
+  ///   err.downcast_ref::<WorkerError>(),
+  ///   Some(WorkerError::Deserialize { .. })
+  /// ));
+  /// ```
   pub fn get_result<R: DeserializeOwned>(self) -> Result<Option<R>> {
-    match self.join() {
-      std::thread::Result::Ok(join) => {
-        let result = join.map(|x| serde_json::from_str::<R>(x.as_str()).unwrap());
-        Ok(result)
+    let canceller = self.canceller.clone();
+    parse_result(self.try_join()?, canceller.as_ref())
+  }
+
+  /// Check whether the task is finished, without blocking
+  pub fn is_finished(&mut self) -> bool {
+    self.poll(Duration::ZERO)
+  }
+
+  /// Get the deserialized result of the task if it's finished, without blocking.
+  /// Returns `None` while the task is running. Once the result has been returned, it can't be retrieved again.
+  /// ```
+  /// use node_workers::WorkerPool;
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 1);
+  /// let mut thread = pool.run_worker("fib2", 20u32);
+  /// let result = loop {
+  ///   if let Some(result) = thread.try_get_result::<u32>() {
+  ///     break result?;
+  ///   }
+  ///   // ... do something else in the meantime
+  /// };
+  /// println!("result: {:?}", result);
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn try_get_result<R: DeserializeOwned>(&mut self) -> Option<Result<Option<R>>> {
+    self.get_result_timeout(Duration::ZERO)
+  }
+
+  /// Wait for the task for at most `timeout`, and get its deserialized result if it's finished.
+  /// Returns `None` if the task is still running after `timeout`. Once the result has been returned, it can't be retrieved again.
+  pub fn get_result_timeout<R: DeserializeOwned>(
+    &mut self,
+    timeout: Duration,
+  ) -> Option<Result<Option<R>>> {
+    if !self.poll(timeout) {
+      return None;
+    }
+    let result = match std::mem::replace(&mut self.inner, Inner::Taken) {
+      Inner::Finished(res) => res,
+      _ => Err(anyhow!("result was already retrieved")),
+    };
+    Some(result.and_then(|res| parse_result(res, self.canceller.as_ref())))
+  }
+
+  /// Block until one of the tasks is finished and return its index, or `None` if `handles` is empty.
+  /// Tasks whose result was already retrieved count as finished.
+  /// ```
+  /// use node_workers::{WorkerPool, WorkerThread};
+  /// # use std::error::Error;
+  ///
+  /// # fn main() -> Result<(), Box<dyn Error>> {
+  /// let mut pool = WorkerPool::setup("examples/worker", 2);
+  /// let mut handles = vec![pool.run_worker("fib2", 30u32), pool.run_worker("fib2", 10u32)];
+  /// while let Some(n) = WorkerThread::wait_any(&mut handles) {
+  ///   let result = handles.remove(n).get_result::<u32>()?;
+  ///   println!("result: {:?}", result);
+  /// }
+  /// # Ok(())
+  /// # }
+  /// ```
+  pub fn wait_any(handles: &mut [WorkerThread]) -> Option<usize> {
+    if handles.is_empty() {
+      return None;
+    }
+    loop {
+      let completions = *COMPLETIONS.lock().unwrap();
+      if let Some(n) = handles.iter_mut().position(|handle| handle.is_finished()) {
+        return Some(n);
+      }
+      let guard = COMPLETIONS.lock().unwrap();
+      let _ = COMPLETED
+        .wait_timeout_while(guard, POLL_INTERVAL, |n| *n == completions)
+        .unwrap();
+    }
+  }
+
+  /// Move the result of the task to `Inner::Finished` if it's available, waiting for at most `timeout`
+  fn poll(&mut self, timeout: Duration) -> bool {
+    match &self.inner {
+      Inner::Finished(_) | Inner::Taken => {}
+      Inner::Task(receiver) => match receiver.recv_timeout(timeout) {
+        std::result::Result::Ok(res) => self.inner = Inner::Finished(res),
+        Err(RecvTimeoutError::Timeout) => return false,
+        Err(RecvTimeoutError::Disconnected) => {
+          self.inner = Inner::Finished(Err(anyhow!("task was abandoned by the pool")))
+        }
+      },
+      Inner::Thread(handle) => {
+        let deadline = Instant::now() + timeout;
+        while !handle.is_finished() {
+          let now = Instant::now();
+          if now >= deadline {
+            return false;
+          }
+          std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+        if let Inner::Thread(handle) = std::mem::replace(&mut self.inner, Inner::Taken) {
+          self.inner = Inner::Finished(join_thread(handle));
+        }
       }
-      std::thread::Result::Err(_) => bail!("thread panicked"),
     }
+    true
   }
 }